@@ -0,0 +1,139 @@
+// Timings for the paths whose speed the library makes claims about.
+// Plain timing rather than a benchmark harness, so it builds on
+// stable without extra dependencies:
+//
+//   cargo bench --bench netcalc            # all of them
+//   cargo bench --bench netcalc -- render  # those whose name matches
+//
+// Each line is the median of a few runs on a fixed pseudo-random input.
+
+use std::time::{Duration, Instant};
+
+use netcalc::netcalc;
+use netcalc::{Prefix, Tree};
+
+const RUNS: usize = 3;
+
+// xorshift, so the input is the same on every run and every machine
+struct Rng(u64);
+
+impl Rng {
+  fn next(&mut self) -> u64 {
+    self.0 ^= self.0 << 13;
+    self.0 ^= self.0 >> 7;
+    self.0 ^= self.0 << 17;
+    self.0
+  }
+}
+
+fn median<T>(mut f: impl FnMut() -> T) -> Duration {
+  let mut times = (0..RUNS)
+    .map(|_| {
+      let start = Instant::now();
+      std::hint::black_box(f());
+      start.elapsed()
+    })
+    .collect::<Vec<_>>();
+  times.sort();
+  times[RUNS / 2]
+}
+
+fn report(name: &str, time: Duration) {
+  println!("{:<40} {:>10.1?}", name, time);
+}
+
+// n random single addresses in 10.0.0.0/8, one "+a.b.c.d/32" per line
+fn random_hosts(rng: &mut Rng, n: usize) -> String {
+  (0..n)
+    .map(|_| {
+      let [_, b, c, d, ..] = rng.next().to_le_bytes();
+      format!("+10.{}.{}.{}/32\n", b, c, d)
+    })
+    .collect()
+}
+
+// the wasm output path: rendering a large set into a single String
+fn render() {
+  let input = random_hosts(&mut Rng(1), 100_000);
+  let tree = netcalc::build_tree("v4", &input).unwrap().optimize();
+
+  report(
+    "render: convert, end to end",
+    median(|| netcalc::convert("v4", "\n", &input).unwrap()),
+  );
+  report(
+    "render: build_tree",
+    median(|| netcalc::build_tree("v4", &input).unwrap()),
+  );
+  report(
+    "render: clone and render",
+    median(|| netcalc::render("v4", "\n", tree.clone()).unwrap()),
+  );
+  report(
+    "render: walk via prefixes()",
+    median(|| tree.prefixes().len()),
+  );
+  report(
+    "render: walk via iter_prefixes",
+    median(|| tree.iter_prefixes().count()),
+  );
+}
+
+// removing many single addresses from a large block at once, against
+// one del each
+fn del_many() {
+  let mut rng = Rng(2);
+  let mut block = Prefix::from(std::net::Ipv4Addr::new(10, 0, 0, 0));
+  block.truncate(8);
+  let tree = Tree::new().add(block);
+  let hosts = (0..5000)
+    .map(|_| {
+      let [_, b, c, d, ..] = rng.next().to_le_bytes();
+      Prefix::from(std::net::Ipv4Addr::new(10, b, c, d))
+    })
+    .collect::<Vec<_>>();
+
+  report(
+    "del_many: del_many",
+    median(|| tree.clone().del_many(hosts.iter().cloned())),
+  );
+  report(
+    "del_many: repeated del",
+    median(|| hosts.iter().cloned().fold(tree.clone(), Tree::del)),
+  );
+}
+
+// add-only rules built in one go with Tree::from_prefixes, against the
+// same rules made to take the general path by a no-op intersection
+fn add_only() {
+  let input = random_hosts(&mut Rng(3), 100_000);
+  let general = format!("&0.0.0.0/0\n{}", input);
+
+  report(
+    "add_only: build_tree",
+    median(|| netcalc::build_tree("v4", &input).unwrap()),
+  );
+  report(
+    "add_only: build_tree, general path",
+    median(|| netcalc::build_tree("v4", &general).unwrap()),
+  );
+}
+
+fn main() {
+  // cargo bench passes "--bench", anything else filters by name
+  let filters = std::env::args()
+    .skip(1)
+    .filter(|arg| !arg.starts_with("--"))
+    .collect::<Vec<_>>();
+  let benches: [(&str, fn()); 3] = [
+    ("render", render),
+    ("del_many", del_many),
+    ("add_only", add_only),
+  ];
+
+  for (name, bench) in benches {
+    if filters.is_empty() || filters.iter().any(|f| name.contains(f.as_str())) {
+      bench();
+    }
+  }
+}
@@ -1,7 +1,175 @@
-use wasm_bindgen::prelude::*;
+use std::io::{self, BufRead, Write};
+
+use failure::bail;
 
 type Result<T> = std::result::Result<T, failure::Error>;
 
-fn main() -> Result<()> {
-  netcalc::convert(sep, s).unwrap_or_else(|err| format!("{}", err))
+const USAGE: &str = "\
+usage: netcalc [--strict] [v4|v6|mixed] [SEP] < rules.txt
+       netcalc aggregate [auto|v4|v6] < cidrs.txt
+       netcalc free UNIVERSE < used.txt
+       netcalc rpsl < whois.txt
+       netcalc csv-ranges [START_COL END_COL] < ranges.csv
+       netcalc sections [v4|v6] < scenarios.txt
+       netcalc explain [v4|v6] < rules.txt
+       netcalc explain-range RANGE
+       netcalc exclude-range RANGE [UNIVERSE]
+       netcalc as-entered [v4|v6] < rules.txt
+       netcalc count [v4|v6] < rules.txt
+       netcalc density [v4|v6] < rules.txt
+       netcalc check [v4|v6] < rules.txt
+       netcalc supernets [v4|v6] < rules.txt
+       netcalc repl";
+
+fn main() {
+  if let Err(err) = run() {
+    eprintln!("{}", err);
+    std::process::exit(1);
+  }
+}
+
+fn run() -> Result<()> {
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  let args: Vec<&str> = args.iter().map(String::as_str).collect();
+  // only sniffed in the arms that read it, so that e.g. --help doesn't
+  // wait for stdin
+  let stdin = || input(io::stdin().lock());
+
+  let output = match args.as_slice() {
+    ["-h" | "--help"] => {
+      println!("{}", USAGE);
+      return Ok(());
+    }
+    ["aggregate"] => netcalc::aggregate("auto", "\n", stdin()?)?,
+    ["aggregate", ver] => netcalc::aggregate(ver, "\n", stdin()?)?,
+    ["free", universe] => netcalc::free("\n", universe, stdin()?)?,
+    ["rpsl"] => netcalc::convert_rpsl("\n", stdin()?)?,
+    ["csv-ranges"] => netcalc::convert_csv_ranges("\n", 0, 1, stdin()?)?,
+    ["csv-ranges", start, end] => {
+      netcalc::convert_csv_ranges("\n", start.parse()?, end.parse()?, stdin()?)?
+    }
+    ["sections"] => netcalc::convert_sections("v4", "\n", stdin()?)?,
+    ["sections", ver] => netcalc::convert_sections(ver, "\n", stdin()?)?,
+    ["repl"] => return repl(stdin()?),
+    ["explain-range", range] => netcalc::explain_range("\n", range)?,
+    ["exclude-range", range] => netcalc::exclude_range("\n", range, None)?,
+    ["exclude-range", range, universe] => {
+      netcalc::exclude_range("\n", range, Some(universe))?
+    }
+    ["explain"] => netcalc::explain("v4", "\n", stdin()?)?,
+    ["explain", ver] => netcalc::explain(ver, "\n", stdin()?)?,
+    ["as-entered"] => netcalc::as_entered("v4", "\n", stdin()?)?,
+    ["as-entered", ver] => netcalc::as_entered(ver, "\n", stdin()?)?,
+    ["supernets"] => netcalc::supernets("v4", "\n", stdin()?)?,
+    ["supernets", ver] => netcalc::supernets(ver, "\n", stdin()?)?,
+    ["check"] => check("v4", stdin()?)?,
+    ["check", ver] => check(ver, stdin()?)?,
+    ["count"] => count("v4", stdin()?)?,
+    ["count", ver] => count(ver, stdin()?)?,
+    ["density"] => density("v4", stdin()?)?,
+    ["density", ver] => density(ver, stdin()?)?,
+    // no comments, and no ranges between misaligned CIDRs
+    ["--strict", rest @ ..] if rest.len() <= 2 => {
+      let fmt = netcalc::Format::new(rest.get(1).unwrap_or(&"\n"));
+      let opts = netcalc::ParseOptions {
+        strict: true,
+        ..Default::default()
+      };
+      let ver = rest.first().unwrap_or(&"v4");
+      netcalc::convert_reader_with(ver, &fmt, &opts, stdin()?)?
+    }
+    [] => netcalc::convert_reader("v4", "\n", stdin()?)?,
+    [ver] => netcalc::convert_reader(ver, "\n", stdin()?)?,
+    [ver, sep] => netcalc::convert_reader(ver, sep, stdin()?)?,
+    _ => bail!("{}", USAGE),
+  };
+
+  if !output.is_empty() {
+    println!("{}", output);
+  }
+  Ok(())
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// stdin, decompressed if it starts with the gzip magic bytes
+fn input(mut stdin: impl BufRead + 'static) -> Result<Box<dyn BufRead>> {
+  if !stdin.fill_buf()?.starts_with(&GZIP_MAGIC) {
+    return Ok(Box::new(stdin));
+  }
+
+  #[cfg(feature = "gzip")]
+  return Ok(Box::new(io::BufReader::new(
+    flate2::bufread::MultiGzDecoder::new(stdin),
+  )));
+
+  #[cfg(not(feature = "gzip"))]
+  bail!("Input is gzip-compressed, build with the gzip feature to read it")
+}
+
+// operands expanding to more prefixes than this are warned about
+const RANGE_WARN_PREFIXES: usize = 8;
+
+fn check(ver: &str, stdin: impl BufRead) -> Result<String> {
+  let warnings = netcalc::check_ranges(ver, RANGE_WARN_PREFIXES, stdin)?;
+  Ok(warnings.join("\n"))
+}
+
+// the number of prefixes the rules would output
+fn count(ver: &str, mut stdin: impl BufRead) -> Result<String> {
+  let mut rules = String::new();
+  stdin.read_to_string(&mut rules)?;
+  Ok(netcalc::count_prefixes(ver, &rules)?.to_string())
+}
+
+// the number of addresses covered under each /8 (v4) or /16 (v6),
+// one block per line
+fn density(ver: &str, mut stdin: impl BufRead) -> Result<String> {
+  let mut rules = String::new();
+  stdin.read_to_string(&mut rules)?;
+  let lines = netcalc::density_report(ver, &rules)?
+    .into_iter()
+    .map(|(block, count)| format!("{} {}", block, count))
+    .collect::<Vec<_>>();
+  Ok(lines.join("\n"))
+}
+
+// applies each line to the set and prints the result, along with the
+// meta-commands .count, .clear and .family v4|v6
+fn repl(stdin: impl BufRead) -> Result<()> {
+  let mut family = "v4".to_string();
+  let mut tree = netcalc::Tree::new();
+
+  prompt()?;
+  for line in stdin.lines() {
+    let line = line?;
+    match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+      [".count"] => println!("{}", tree.count_prefixes()),
+      [".clear"] => tree = netcalc::Tree::new(),
+      // the sets of the two families don't mix
+      [".family", ver @ ("v4" | "v6")] => {
+        family = ver.to_string();
+        tree = netcalc::Tree::new();
+      }
+      [cmd, ..] if cmd.starts_with('.') => {
+        eprintln!("Unknown command: {}", line)
+      }
+      _ => match netcalc::apply_line(&family, tree.clone(), &line) {
+        Ok(new_tree) => {
+          tree = new_tree;
+          println!("{}", netcalc::render(&family, "\n", tree.clone())?);
+        }
+        Err(err) => eprintln!("{}", err),
+      },
+    }
+    prompt()?;
+  }
+
+  Ok(())
+}
+
+fn prompt() -> Result<()> {
+  eprint!("> ");
+  io::stderr().flush()?;
+  Ok(())
 }
@@ -1,7 +1,30 @@
-mod netcalc;
+pub mod netcalc;
 
 use wasm_bindgen::prelude::*;
 
+// the set algebra, for use as a native library
+pub use netcalc::{
+  Bit, Family, Format, HostBitsFinding, IpPrefix, NetcalcError, Notation,
+  ParseOptions, Prefix, RangeFit, Tree, DEFAULT_MAX_LINE_LEN,
+};
+
+pub use netcalc::{
+  aggregate, apply_line, as_entered, build_tree, build_tree_lossy,
+  check_host_bits, check_ranges, convert_csv_ranges, convert_reader,
+  convert_reader_with, convert_rpsl, convert_sections, count_prefixes,
+  density_report, exclude_range, explain, explain_range, fit_range, free,
+  hosts_in_prefix, render, subnets_of_length, supernets,
+};
+
+// the same with the parse options, e.g. to lift the line length limit
+pub use netcalc::{
+  aggregate_with, as_entered_with, build_tree_lossy_with, build_tree_with,
+  check_host_bits_with, check_ranges_with, convert_csv_ranges_with,
+  convert_rpsl_with, convert_sections_with, count_prefixes_with,
+  density_report_with, explain_with, free_with, supernets_with,
+};
+
+#[cfg(target_arch = "wasm32")]
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
@@ -10,3 +33,140 @@ pub fn convert(ver: &str, sep: &str, s: &str) -> String {
   console_error_panic_hook::set_once();
   netcalc::convert(ver, sep, s).unwrap_or_else(|err| format!("{}", err))
 }
+
+#[wasm_bindgen]
+pub fn convert_with_trailing_sep(ver: &str, sep: &str, s: &str) -> String {
+  console_error_panic_hook::set_once();
+  let fmt = netcalc::Format {
+    trailing_sep: true,
+    ..netcalc::Format::new(sep)
+  };
+  let opts = netcalc::ParseOptions::default();
+  netcalc::convert_with(ver, &fmt, &opts, s)
+    .unwrap_or_else(|err| format!("{}", err))
+}
+
+// one prefix per line, indented under their supernets of group_len
+#[wasm_bindgen]
+pub fn convert_grouped(ver: &str, group_len: usize, s: &str) -> String {
+  console_error_panic_hook::set_once();
+  let fmt = netcalc::Format {
+    group_len: Some(group_len),
+    ..netcalc::Format::new("\n")
+  };
+  let opts = netcalc::ParseOptions::default();
+  netcalc::convert_with(ver, &fmt, &opts, s)
+    .unwrap_or_else(|err| format!("{}", err))
+}
+
+// the size of the output of convert, as a number or an error message
+#[wasm_bindgen]
+pub fn convert_count(ver: &str, s: &str) -> String {
+  console_error_panic_hook::set_once();
+  netcalc::count_prefixes(ver, s)
+    .map(|count| count.to_string())
+    .unwrap_or_else(|err| format!("{}", err))
+}
+
+#[wasm_bindgen]
+pub fn cidr_range(ver: &str, s: &str) -> String {
+  console_error_panic_hook::set_once();
+  netcalc::cidr_range(ver, s)
+    .map(|(network, broadcast)| format!("{}-{}", network, broadcast))
+    .unwrap_or_else(|err| format!("{}", err))
+}
+
+// the result of a conversion, with either the output or the error
+// message and its code set
+#[wasm_bindgen(getter_with_clone)]
+pub struct ConvertResult {
+  pub output: Option<String>,
+  pub error: Option<String>,
+  pub code: Option<String>,
+  // the number of rules applied, 0 when the input is only blanks and
+  // comments
+  pub ops: usize,
+}
+
+#[wasm_bindgen]
+pub fn convert_structured(ver: &str, sep: &str, s: &str) -> ConvertResult {
+  console_error_panic_hook::set_once();
+  let fmt = netcalc::Format::new(sep);
+  let opts = netcalc::ParseOptions::default();
+  match netcalc::convert_counted(ver, &fmt, &opts, s) {
+    Ok((output, ops)) => ConvertResult {
+      output: Some(output),
+      error: None,
+      code: None,
+      ops,
+    },
+    Err(err) => ConvertResult {
+      output: None,
+      error: Some(err.to_string()),
+      code: Some(netcalc::NetcalcError::code_of(&err).to_string()),
+      ops: 0,
+    },
+  }
+}
+
+// both families of a mixed input, rendered separately. On error both
+// are empty and error is set.
+#[wasm_bindgen(getter_with_clone)]
+pub struct DualResult {
+  pub v4: String,
+  pub v6: String,
+  pub error: Option<String>,
+}
+
+#[wasm_bindgen]
+pub fn convert_dual(sep: &str, s: &str) -> DualResult {
+  console_error_panic_hook::set_once();
+  match netcalc::convert_dual(sep, s) {
+    Ok((v4, v6)) => DualResult {
+      v4,
+      v6,
+      error: None,
+    },
+    Err(err) => DualResult {
+      v4: String::new(),
+      v6: String::new(),
+      error: Some(err.to_string()),
+    },
+  }
+}
+
+// the first limit prefixes of convert, for previewing huge outputs.
+// more is the number of prefixes left out, for a "...and N more".
+#[wasm_bindgen(getter_with_clone)]
+pub struct PreviewResult {
+  pub output: String,
+  pub truncated: bool,
+  pub more: usize,
+  pub error: Option<String>,
+}
+
+#[wasm_bindgen]
+pub fn convert_preview(
+  ver: &str,
+  sep: &str,
+  limit: usize,
+  s: &str,
+) -> PreviewResult {
+  console_error_panic_hook::set_once();
+  let fmt = netcalc::Format::new(sep);
+  let opts = netcalc::ParseOptions::default();
+  match netcalc::convert_preview(ver, &fmt, &opts, s, limit) {
+    Ok((output, more)) => PreviewResult {
+      output,
+      truncated: more > 0,
+      more,
+      error: None,
+    },
+    Err(err) => PreviewResult {
+      output: String::new(),
+      truncated: false,
+      more: 0,
+      error: Some(err.to_string()),
+    },
+  }
+}
@@ -1,6 +1,7 @@
 use failure::ensure;
 use itertools::{EitherOrBoth, Itertools};
 use std::cmp::Ordering;
+use std::net::{Ipv4Addr, Ipv6Addr};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Bit {
@@ -12,6 +13,12 @@ use Bit::*;
 
 type Result<T> = std::result::Result<T, failure::Error>;
 
+// the largest universe to_bitmap accepts, in host bits
+const MAX_BITMAP_BITS: usize = 24;
+
+// at most two "*" octets
+const MAX_WILDCARD_EXPANSION: usize = 256 * 256;
+
 impl From<Bit> for u8 {
   fn from(b: Bit) -> u8 {
     match b {
@@ -40,6 +47,68 @@ impl From<&[u8]> for Prefix {
   }
 }
 
+impl From<Ipv4Addr> for Prefix {
+  fn from(addr: Ipv4Addr) -> Self {
+    let mut prefix = Prefix::empty();
+    for byte in addr.octets() {
+      prefix.extend(Prefix::from_u8(byte));
+    }
+    prefix
+  }
+}
+
+impl From<Ipv6Addr> for Prefix {
+  fn from(addr: Ipv6Addr) -> Self {
+    let mut prefix = Prefix::empty();
+    for byte in addr.octets() {
+      prefix.extend(Prefix::from_u8(byte));
+    }
+    prefix
+  }
+}
+
+#[cfg(feature = "ipnet")]
+impl From<ipnet::Ipv4Net> for Prefix {
+  fn from(net: ipnet::Ipv4Net) -> Self {
+    let mut prefix = Prefix::from(net.network());
+    prefix.truncate(net.prefix_len() as usize);
+    prefix
+  }
+}
+
+#[cfg(feature = "ipnet")]
+impl From<ipnet::Ipv6Net> for Prefix {
+  fn from(net: ipnet::Ipv6Net) -> Self {
+    let mut prefix = Prefix::from(net.network());
+    prefix.truncate(net.prefix_len() as usize);
+    prefix
+  }
+}
+
+// fails for prefixes longer than the addresses of the family
+#[cfg(feature = "ipnet")]
+impl TryFrom<Prefix> for ipnet::Ipv4Net {
+  type Error = failure::Error;
+
+  fn try_from(prefix: Prefix) -> Result<Self> {
+    ensure!(prefix.len() <= 32, "Prefix too long for IPv4");
+    let addr = Ipv4Addr::from(prefix.network(32).to_u64()? as u32);
+    Ok(ipnet::Ipv4Net::new(addr, prefix.len() as u8)?)
+  }
+}
+
+#[cfg(feature = "ipnet")]
+impl TryFrom<Prefix> for ipnet::Ipv6Net {
+  type Error = failure::Error;
+
+  fn try_from(prefix: Prefix) -> Result<Self> {
+    ensure!(prefix.len() <= 128, "Prefix too long for IPv6");
+    let halves = prefix.network(128).chunks(64)?;
+    let addr = Ipv6Addr::from((halves[0] as u128) << 64 | halves[1] as u128);
+    Ok(ipnet::Ipv6Net::new(addr, prefix.len() as u8)?)
+  }
+}
+
 impl Prefix {
   pub fn empty() -> Self {
     Self(vec![])
@@ -104,12 +173,21 @@ impl Prefix {
   pub fn len(&self) -> usize {
     self.0.len()
   }
+  pub fn bits(&self) -> impl Iterator<Item = Bit> + '_ {
+    self.0.iter().copied()
+  }
+  pub fn as_slice(&self) -> &[Bit] {
+    &self.0
+  }
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
   pub fn truncate(&mut self, n: usize) {
     self.0.truncate(n)
   }
 
   pub fn split(mut self) -> Result<(Bit, Self)> {
-    ensure!(self.len() > 0, "Cannot split on empty prefix");
+    ensure!(!self.is_empty(), "Cannot split on empty prefix");
     let tail = self.0.split_off(1);
     let head = self.0[0];
     Ok((head, Self(tail)))
@@ -118,6 +196,84 @@ impl Prefix {
   pub fn right_pad(&mut self, new_len: usize, bit: Bit) {
     self.0.resize(new_len, bit)
   }
+
+  // the first and last address under the prefix, as full-width
+  // prefixes of total_bits
+  pub fn network(&self, total_bits: usize) -> Prefix {
+    let mut out = self.clone();
+    out.right_pad(total_bits, B0);
+    out
+  }
+  pub fn broadcast(&self, total_bits: usize) -> Prefix {
+    let mut out = self.clone();
+    out.right_pad(total_bits, B1);
+    out
+  }
+
+  // whether addr falls under this prefix, i.e. the prefix's bits are
+  // the leading bits of addr
+  pub fn matches(&self, addr: &Prefix) -> bool {
+    addr.0.starts_with(&self.0)
+  }
+
+  // the number of differing bits, None if the lengths differ
+  pub fn hamming_distance(&self, other: &Self) -> Option<usize> {
+    if self.len() != other.len() {
+      return None;
+    }
+    Some(
+      self
+        .0
+        .iter()
+        .zip(other.0.iter())
+        .filter(|(a, b)| a != b)
+        .count(),
+    )
+  }
+
+  // treat the prefix as a big-endian integer of its current length
+  // and add one to it, returns None on overflow
+  pub fn increment(&self) -> Option<Self> {
+    let mut out = self.clone();
+    for bit in out.0.iter_mut().rev() {
+      match bit {
+        B0 => {
+          *bit = B1;
+          return Some(out);
+        }
+        B1 => *bit = B0,
+      }
+    }
+    None
+  }
+
+  // same as increment but add n, returns None on overflow
+  pub fn checked_add(&self, mut n: u128) -> Option<Self> {
+    let mut out = self.clone();
+    let mut carry = 0;
+    for bit in out.0.iter_mut().rev() {
+      let sum = u8::from(*bit) + (n & 1) as u8 + carry;
+      *bit = if sum & 1 == 0 { B0 } else { B1 };
+      carry = sum >> 1;
+      n >>= 1;
+    }
+    (n == 0 && carry == 0).then_some(out)
+  }
+
+  // same as increment but subtract one, returns None on underflow
+  pub fn decrement(&self) -> Option<Self> {
+    let mut out = self.clone();
+    for bit in out.0.iter_mut().rev() {
+      match bit {
+        B1 => {
+          *bit = B0;
+          return Some(out);
+        }
+        B0 => *bit = B1,
+      }
+    }
+    None
+  }
 }
 
 impl PartialOrd for Prefix {
@@ -152,6 +308,42 @@ pub enum Tree {
 }
 use Tree::*;
 
+// A structural order, consistent with the derived Eq: Unsat < Mixed <
+// Sat, with Mixed trees compared left then right. Equal sets only
+// compare equal in their canonical (optimized) form, so optimize the
+// trees before sorting or deduplicating them.
+impl Ord for Tree {
+  fn cmp(&self, other: &Self) -> Ordering {
+    use Ordering::{Equal, Greater, Less};
+
+    match (self, other) {
+      (Unsat, Unsat) | (Sat, Sat) => Equal,
+      (Unsat, _) | (_, Sat) => Less,
+      (_, Unsat) | (Sat, _) => Greater,
+      (Mixed(l1, r1), Mixed(l2, r2)) => l1.cmp(l2).then_with(|| r1.cmp(r2)),
+    }
+  }
+}
+
+impl PartialOrd for Tree {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Default for Tree {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+// the costs worked out by cover_costs for a subtree, kept alongside
+// those of its children while a cover is chosen
+struct CoverCosts {
+  costs: Vec<Option<u128>>,
+  children: Option<Box<(CoverCosts, CoverCosts)>>,
+}
+
 impl Tree {
   pub fn new() -> Self {
     Unsat
@@ -163,9 +355,86 @@ impl Tree {
 
   pub fn from_range(start: &Prefix, end: &Prefix) -> Result<Self> {
     ensure!(start <= end, "In a range, start must be <= end");
+
+    // fast paths for the whole space and a single address
+    let all_zeros = start.0.iter().all(|b| *b == B0);
+    let all_ones = end.0.iter().all(|b| *b == B1);
+    if start.len() == end.len() && all_zeros && all_ones {
+      return Ok(Sat);
+    }
+    if start == end {
+      return Ok(Tree::new().add(start.clone()));
+    }
+
     Ok(Self::from_range_at(Prefix::empty(), start, end))
   }
 
+  // like from_range, but end is the first address after the range, as
+  // in 10.0.0.0..10.0.1.0 for 10.0.0.0-10.0.0.255. The range is empty
+  // if start == end.
+  pub fn from_range_exclusive(start: &Prefix, end: &Prefix) -> Result<Self> {
+    ensure!(start <= end, "In a range, start must be <= end");
+    if start == end {
+      return Ok(Unsat);
+    }
+    // start < end, so end is never the lowest address
+    Self::from_range(start, &end.decrement().unwrap())
+  }
+
+  // the set described by a pattern of octets where None is a "*"
+  // wildcard, e.g. 10.*.5.0/24, built as the union of every concrete
+  // prefix it expands to. wildcards past the prefix length are
+  // truncated away and don't count towards the expansion.
+  pub fn from_wildcard_prefix(
+    octets: &[Option<u8>],
+    len: usize,
+  ) -> Result<Self> {
+    ensure!(len <= octets.len() * 8, "Prefix length exceeds the pattern");
+
+    // trailing wildcards just shorten the prefix, 10.*.*.* is 10.0.0.0/8
+    let tail = octets[..len.div_ceil(8)]
+      .iter()
+      .rposition(|o| o.is_some())
+      .map_or(0, |i| i + 1);
+    let len = len.min(tail * 8);
+
+    let wildcards = octets
+      .iter()
+      .take(len.div_ceil(8))
+      .filter(|o| o.is_none())
+      .count();
+    ensure!(
+      256usize
+        .checked_pow(wildcards as u32)
+        .is_some_and(|n| n <= MAX_WILDCARD_EXPANSION),
+      "Wildcard pattern expands to more than {} prefixes",
+      MAX_WILDCARD_EXPANSION
+    );
+
+    let mut prefixes = vec![Prefix::empty()];
+    for (i, octet) in octets.iter().enumerate() {
+      let values = match octet {
+        None if i * 8 < len => 0..=255,
+        _ => octet.unwrap_or(0)..=octet.unwrap_or(0),
+      };
+      prefixes = prefixes
+        .iter()
+        .flat_map(|p| {
+          values.clone().map(move |b| {
+            let mut p = p.clone();
+            p.extend(Prefix::from_u8(b));
+            p
+          })
+        })
+        .collect();
+    }
+
+    Ok(prefixes.into_iter().fold(Tree::new(), |tree, mut p| {
+      p.truncate(len);
+      tree.add(p)
+    }))
+  }
+
   fn from_range_at(curr: Prefix, start: &Prefix, end: &Prefix) -> Self {
     if &curr < start || &curr > end {
       return Unsat;
@@ -211,41 +480,368 @@ impl Tree {
     }
   }
 
+  // the subtrees that other doesn't overlap are kept as they are, so
+  // a set it doesn't overlap at all comes back untouched rather than
+  // optimized. That's what makes subtracting many small disjoint
+  // blocks cheap. Wherever something is removed, the result is
+  // optimized from there down, as before.
   pub fn difference(self, other: Self) -> Self {
+    self.difference_changed(other).0
+  }
+
+  // the difference, and whether it's any different from self. Telling
+  // the unchanged subtrees apart as they're walked is the same as
+  // checking for an overlap first, without a separate walk.
+  fn difference_changed(self, other: Self) -> (Self, bool) {
     match (self, other) {
       // sat or unsat on the right
-      (_, Sat) => Unsat,
-      (a, Unsat) => a,
+      (Unsat, _) => (Unsat, false),
+      (a, Unsat) => (a, false),
+      (_, Sat) => (Unsat, true),
+      // sat on the left
+      (Sat, b) => (b.flip(), true),
+      // both mixed
+      (Mixed(mut a0, mut a1), Mixed(b0, b1)) => {
+        let (l, l_changed) = std::mem::take(&mut *a0).difference_changed(*b0);
+        let (r, r_changed) = std::mem::take(&mut *a1).difference_changed(*b1);
+        *a0 = l;
+        *a1 = r;
+        if l_changed || r_changed {
+          (Mixed(a0, a1).optimize(), true)
+        } else {
+          (Mixed(a0, a1), false)
+        }
+      }
+    }
+  }
+
+  pub fn intersection(self, other: Self) -> Self {
+    match (self, other) {
+      // sat or unsat on the right
+      (a, Sat) => a,
+      (_, Unsat) => Unsat,
       // sat or unsat on the left
-      (Sat, b) => b.flip(),
+      (Sat, b) => b,
       (Unsat, _) => Unsat,
       // both mixed
-      (Mixed(a0, a1), Mixed(b0, b1)) => {
-        let l = a0.difference(*b0);
-        let r = a1.difference(*b1);
+      (Mixed(l1, r1), Mixed(l2, r2)) => {
+        let l = l1.intersection(*l2);
+        let r = r1.intersection(*r2);
         Self::mixed(l, r).optimize()
       }
     }
   }
 
-  pub fn add(self, prefix: Prefix) -> Self {
-    if prefix.len() == 0 {
-      return Sat;
+  // the union of all the trees, merged pairwise in rounds so that
+  // intermediate trees stay small
+  pub fn merge_all(mut trees: Vec<Tree>) -> Tree {
+    while trees.len() > 1 {
+      let mut merged = Vec::with_capacity(trees.len().div_ceil(2));
+      let mut iter = trees.into_iter();
+      while let Some(a) = iter.next() {
+        merged.push(match iter.next() {
+          Some(b) => a.union(b),
+          None => a,
+        });
+      }
+      trees = merged;
     }
-    let (h, t) = prefix.split().unwrap();
-    match (self, h) {
-      (Sat, _) => Sat,
-      (Unsat, B0) => Self::mixed(Unsat.add(t), Unsat),
-      (Unsat, B1) => Self::mixed(Unsat, Unsat.add(t)),
-      (Mixed(l, r), B0) => Mixed(Box::new(l.add(t)), r),
-      (Mixed(l, r), B1) => Mixed(l, Box::new(r.add(t))),
+    trees.pop().unwrap_or(Unsat)
+  }
+
+  // borrowing variants of the above, cloning only the parts of either
+  // tree that end up in the result
+  pub fn union_ref(&self, other: &Self) -> Self {
+    match (self, other) {
+      (_, Sat) | (Sat, _) => Sat,
+      (a, Unsat) => a.clone(),
+      (Unsat, b) => b.clone(),
+      (Mixed(l1, r1), Mixed(l2, r2)) => {
+        Self::mixed(l1.union_ref(l2), r1.union_ref(r2)).optimize()
+      }
+    }
+  }
+
+  pub fn difference_ref(&self, other: &Self) -> Self {
+    match (self, other) {
+      (_, Sat) | (Unsat, _) => Unsat,
+      (a, Unsat) => a.clone(),
+      (Sat, b) => b.clone().flip(),
+      (Mixed(a0, a1), Mixed(b0, b1)) => {
+        Self::mixed(a0.difference_ref(b0), a1.difference_ref(b1)).optimize()
+      }
+    }
+  }
+
+  pub fn intersection_ref(&self, other: &Self) -> Self {
+    match (self, other) {
+      (_, Unsat) | (Unsat, _) => Unsat,
+      (a, Sat) => a.clone(),
+      (Sat, b) => b.clone(),
+      (Mixed(l1, r1), Mixed(l2, r2)) => {
+        Self::mixed(l1.intersection_ref(l2), r1.intersection_ref(r2)).optimize()
+      }
+    }
+  }
+
+  // returns (self - other, self & other), i.e. what's kept and what's
+  // removed by the difference, computed in a single walk
+  pub fn difference_with_report(self, other: Self) -> (Self, Self) {
+    match (self, other) {
+      // sat or unsat on the right
+      (a, Sat) => (Unsat, a),
+      (a, Unsat) => (a, Unsat),
+      // sat or unsat on the left
+      (Sat, b) => (b.clone().flip(), b),
+      (Unsat, _) => (Unsat, Unsat),
+      // both mixed
+      (Mixed(a0, a1), Mixed(b0, b1)) => {
+        let (kept_l, removed_l) = a0.difference_with_report(*b0);
+        let (kept_r, removed_r) = a1.difference_with_report(*b1);
+        let kept = Self::mixed(kept_l, kept_r).optimize();
+        let removed = Self::mixed(removed_l, removed_r).optimize();
+        (kept, removed)
+      }
+    }
+  }
+
+  // the addresses in the universe not in the set
+  pub fn complement_within(self, universe: &Prefix) -> Self {
+    Tree::new().add(universe.clone()).difference(self)
+  }
+
+  // the longest prefix containing the whole set, None if it's empty
+  pub fn covering_prefix(&self) -> Option<Prefix> {
+    let mut prefix = Prefix::empty();
+    let mut node = self;
+    loop {
+      match node {
+        Unsat => return None,
+        Sat => return Some(prefix),
+        Mixed(l, r) => match (l.is_empty(), r.is_empty()) {
+          (true, true) => return None,
+          (false, true) => {
+            prefix.push(B0);
+            node = l;
+          }
+          (true, false) => {
+            prefix.push(B1);
+            node = r;
+          }
+          (false, false) => return Some(prefix),
+        },
+      }
+    }
+  }
+
+  // the gaps inside the set's covering prefix, i.e. the complement
+  // bounded by the smallest block around the set
+  pub fn complement_in_bounds(&self) -> Self {
+    match self.covering_prefix() {
+      Some(bounds) => self.clone().complement_within(&bounds),
+      None => Unsat,
+    }
+  }
+
+  // moves the part of the set within `from` to the same relative
+  // position within `to`, dropping everything outside of `from`
+  pub fn rebase(&self, from: &Prefix, to: &Prefix) -> Result<Self> {
+    ensure!(
+      from.len() == to.len(),
+      "Cannot rebase between prefixes of different lengths"
+    );
+    Ok(Self::graft(self.subtree(from), to))
+  }
+
+  // the part of the tree under the prefix, relative to the prefix
+  fn subtree(&self, prefix: &Prefix) -> Self {
+    let mut tree = self;
+    for bit in prefix.0.iter() {
+      match (tree, bit) {
+        (Sat, _) => return Sat,
+        (Unsat, _) => return Unsat,
+        (Mixed(l, _), B0) => tree = l,
+        (Mixed(_, r), B1) => tree = r,
+      }
+    }
+    tree.clone()
+  }
+
+  // the reverse of subtree: places the tree under the prefix
+  fn graft(tree: Self, prefix: &Prefix) -> Self {
+    let mut tree = tree;
+    for bit in prefix.0.iter().rev() {
+      tree = match bit {
+        B0 => Self::mixed(tree, Unsat),
+        B1 => Self::mixed(Unsat, tree),
+      };
+    }
+    tree.optimize()
+  }
+
+  // one bool per address of the universe, in order, telling whether
+  // the address is in the set. Only for universes of at most 2^24
+  // addresses.
+  pub fn to_bitmap(
+    &self,
+    universe: &Prefix,
+    total_bits: u32,
+  ) -> Result<Vec<bool>> {
+    ensure!(
+      universe.len() <= total_bits as usize,
+      "Universe is longer than the address"
+    );
+    let host_bits = total_bits as usize - universe.len();
+    ensure!(
+      host_bits <= MAX_BITMAP_BITS,
+      "Universe too large for a bitmap, at most 2^{} addresses",
+      MAX_BITMAP_BITS
+    );
+
+    let mut bitmap = Vec::with_capacity(1 << host_bits);
+    self.subtree(universe).fill_bitmap(host_bits, &mut bitmap);
+    Ok(bitmap)
+  }
+
+  fn fill_bitmap(&self, host_bits: usize, bitmap: &mut Vec<bool>) {
+    match self {
+      Sat => bitmap.resize(bitmap.len() + (1 << host_bits), true),
+      Unsat => bitmap.resize(bitmap.len() + (1 << host_bits), false),
+      // nothing deeper than the address is an address, as in
+      // count_addresses
+      Mixed(_, _) if host_bits == 0 => bitmap.push(false),
+      Mixed(l, r) => {
+        l.fill_bitmap(host_bits - 1, bitmap);
+        r.fill_bitmap(host_bits - 1, bitmap);
+      }
     }
   }
 
+  // allocates the lowest free block of new_len bits within the
+  // universe, returning it along with the set with it added. None if
+  // there's no such block left.
+  pub fn carve(self, universe: &Prefix, new_len: u8) -> Option<(Prefix, Tree)> {
+    if (new_len as usize) < universe.len() {
+      return None;
+    }
+
+    let block = self
+      .subtree(universe)
+      .first_free(&mut universe.clone(), new_len as usize)?;
+    Some((block.clone(), self.add(block)))
+  }
+
+  fn first_free(&self, path: &mut Prefix, len: usize) -> Option<Prefix> {
+    match self {
+      Sat => None,
+      Unsat => Some(path.network(len)),
+      Mixed(..) if path.len() == len => None,
+      Mixed(l, r) => {
+        for (bit, child) in [(B0, l), (B1, r)] {
+          path.push(bit);
+          let block = child.first_free(path, len);
+          path.truncate(path.len() - 1);
+          if block.is_some() {
+            return block;
+          }
+        }
+        None
+      }
+    }
+  }
+
+  // every other block of leaf_len bits within the universe, starting
+  // with the first, so that no two of them can be merged
+  #[cfg(any(test, feature = "testing"))]
+  pub fn alternating(universe: &Prefix, leaf_len: u8) -> Result<Self> {
+    let leaf_len = leaf_len as usize;
+    ensure!(
+      universe.len() <= leaf_len,
+      "Leaf length must be at least the universe length"
+    );
+    if leaf_len == universe.len() {
+      return Ok(Tree::new().add(universe.clone()));
+    }
+
+    let mut tree = Self::mixed(Sat, Unsat);
+    for _ in universe.len() + 1..leaf_len {
+      tree = Self::mixed(tree.clone(), tree);
+    }
+    Ok(Self::graft(tree, universe))
+  }
+
+  // the number of maximal free blocks within the universe
+  pub fn count_gaps(&self, universe: &Prefix) -> usize {
+    self.clone().complement_within(universe).count_prefixes()
+  }
+
+  // the largest (shortest) free block within the universe, the lowest
+  // one if there are several of the same size
+  pub fn largest_free_block(&self, universe: &Prefix) -> Option<Prefix> {
+    let free = self.clone().complement_within(universe).optimize();
+    free.iter_prefixes().min_by_key(Prefix::len)
+  }
+
+  // splits the set into the parts inside and outside the boundary
+  pub fn partition(self, boundary: &Prefix) -> (Self, Self) {
+    let boundary = Tree::new().add(boundary.clone());
+    let (outside, inside) = self.difference_with_report(boundary);
+    (inside, outside)
+  }
+
+  // set union with a single prefix, not to be confused with ops::Add
+  #[allow(clippy::should_implement_trait)]
+  pub fn add(mut self, prefix: Prefix) -> Self {
+    self.insert(&prefix);
+    self
+  }
+
+  // same as add, but in place, walking down the existing nodes rather
+  // than rebuilding the path from the root
+  pub fn insert(&mut self, prefix: &Prefix) {
+    let mut node = self;
+    for bit in prefix.bits() {
+      let (l, r) = match node {
+        Sat => return,
+        Unsat => {
+          *node = Self::mixed(Unsat, Unsat);
+          match node {
+            Mixed(l, r) => (l, r),
+            _ => unreachable!(),
+          }
+        }
+        Mixed(l, r) => (l, r),
+      };
+      node = match bit {
+        B0 => l,
+        B1 => r,
+      };
+    }
+    *node = Sat;
+  }
+
+  // the optimized set of the prefixes, inserted in place and optimized
+  // once at the end
+  pub fn from_prefixes(prefixes: impl IntoIterator<Item = Prefix>) -> Self {
+    let mut tree = Tree::new();
+    for prefix in prefixes {
+      tree.insert(&prefix);
+    }
+    tree.optimize()
+  }
+
   pub fn del(self, prefix: Prefix) -> Self {
     self.flip().add(prefix).flip()
   }
 
+  // same as calling del with each prefix, but the deletions are
+  // collected into a tree and removed in a single pass rather than
+  // flipping the whole set twice per prefix
+  pub fn del_many(self, prefixes: impl IntoIterator<Item = Prefix>) -> Self {
+    let deleted = prefixes.into_iter().fold(Tree::new(), Tree::add);
+    self.difference(deleted)
+  }
+
   pub fn add_tree(self, tree: Tree) -> Self {
     self.union(tree)
   }
@@ -254,6 +850,141 @@ impl Tree {
     self.difference(tree)
   }
 
+  pub fn is_empty(&self) -> bool {
+    self.iter_prefixes().next().is_none()
+  }
+
+  // whether the two sets have any address in common
+  pub fn overlaps(&self, other: &Self) -> bool {
+    match (self, other) {
+      (Unsat, _) | (_, Unsat) => false,
+      (Sat, b) => !b.is_empty(),
+      (a, Sat) => !a.is_empty(),
+      (Mixed(l1, r1), Mixed(l2, r2)) => l1.overlaps(l2) || r1.overlaps(r2),
+    }
+  }
+
+  // one node per line, indented by depth and labelled with the bit
+  // path leading to it, e.g.
+  //
+  //   (root) Mixed
+  //     0 Sat
+  //     1 Mixed
+  //       10 Unsat
+  //       11 Sat
+  pub fn pretty(&self) -> String {
+    let mut out = String::new();
+    self.pretty_into(&mut String::new(), &mut out);
+    out
+  }
+
+  fn pretty_into(&self, path: &mut String, out: &mut String) {
+    let label = if path.is_empty() {
+      "(root)"
+    } else {
+      path.as_str()
+    };
+    let kind = match self {
+      Sat => "Sat",
+      Unsat => "Unsat",
+      Mixed(..) => "Mixed",
+    };
+    out.push_str(&format!("{}{} {}\n", "  ".repeat(path.len()), label, kind));
+
+    if let Mixed(l, r) = self {
+      for (bit, child) in [('0', l), ('1', r)] {
+        path.push(bit);
+        child.pretty_into(path, out);
+        path.pop();
+      }
+    }
+  }
+
+  // whether the two trees cover the same addresses, however they are
+  // split into nodes. add keeps the split of its inputs (which
+  // optimize_bounded relies on), so == may differ where this doesn't.
+  pub fn coverage_eq(&self, other: &Self) -> bool {
+    self.clone().optimize() == other.clone().optimize()
+  }
+
+  // whether every address of other is also in the set, stopping at
+  // the first uncovered part of other
+  pub fn contains_tree(&self, other: &Self) -> bool {
+    match (self, other) {
+      (Sat, _) | (_, Unsat) => true,
+      (Unsat, b) => b.is_empty(),
+      (Mixed(l, r), Sat) => l.contains_tree(other) && r.contains_tree(other),
+      (Mixed(l1, r1), Mixed(l2, r2)) => {
+        l1.contains_tree(l2) && r1.contains_tree(r2)
+      }
+    }
+  }
+
+  // Lossy: replaces any subtree of which at least fill_ratio (0 to 1)
+  // of the addresses are covered by a single Sat leaf, trading some
+  // over-coverage for fewer prefixes. The fill is always measured on
+  // the original set, so over-coverage doesn't accumulate upwards.
+  // Like count_addresses, leaves deeper than total_bits hold no
+  // addresses, so they never count towards the fill.
+  pub fn simplify(self, fill_ratio: f64, total_bits: u32) -> Self {
+    self.simplify_with_fill(fill_ratio, 0, total_bits).0
+  }
+
+  fn simplify_with_fill(
+    self,
+    fill_ratio: f64,
+    depth: u32,
+    total_bits: u32,
+  ) -> (Self, f64) {
+    match self {
+      Sat if depth > total_bits => (Sat, 0.0),
+      Sat => (Sat, 1.0),
+      Unsat => (Unsat, 0.0),
+      Mixed(l, r) => {
+        let (l, l_fill) =
+          l.simplify_with_fill(fill_ratio, depth + 1, total_bits);
+        let (r, r_fill) =
+          r.simplify_with_fill(fill_ratio, depth + 1, total_bits);
+        let fill = (l_fill + r_fill) / 2.0;
+        if fill >= fill_ratio {
+          (Sat, fill)
+        } else {
+          (Self::mixed(l, r).optimize(), fill)
+        }
+      }
+    }
+  }
+
+  // the leaf (in the optimized tree) with an address that differs
+  // from target by the fewest bits, i.e. the leaf whose prefix has the
+  // least hamming distance to the leading bits of target. Ties go to
+  // the lower leaf.
+  pub fn nearest(&self, target: &Prefix) -> Option<Prefix> {
+    self
+      .clone()
+      .optimize()
+      .iter_prefixes()
+      .filter(|leaf| leaf.len() <= target.len())
+      .min_by_key(|leaf| {
+        let mut bits = target.clone();
+        bits.truncate(leaf.len());
+        leaf.hamming_distance(&bits)
+      })
+  }
+
+  // whether every address under the prefix is in the set
+  pub fn contains(&self, prefix: &Prefix) -> bool {
+    self.subtree(prefix).optimize() == Sat
+  }
+
+  pub fn contains_ipv4(&self, addr: Ipv4Addr) -> bool {
+    self.contains(&addr.into())
+  }
+
+  pub fn contains_ipv6(&self, addr: Ipv6Addr) -> bool {
+    self.contains(&addr.into())
+  }
+
   pub fn optimize(self) -> Self {
     match self {
       Sat => Sat,
@@ -266,46 +997,603 @@ impl Tree {
     }
   }
 
-  pub fn prefixes(&self) -> Vec<Prefix> {
-    self.clone().optimize().prefixes_from(Prefix::empty())
+  // walks the tree as is (without optimizing), calling `sat` with the
+  // prefix of each Sat leaf and combining siblings with `merge`
+  pub fn fold<A: Clone>(
+    &self,
+    sat: impl Fn(&Prefix) -> A,
+    unsat: A,
+    merge: impl Fn(A, A) -> A,
+  ) -> A {
+    self.fold_from(&mut Prefix::empty(), &sat, &unsat, &merge)
   }
 
-  fn prefixes_from(self, prefix: Prefix) -> Vec<Prefix> {
+  // `path` is shared across the walk to avoid cloning it on each node
+  fn fold_from<A: Clone>(
+    &self,
+    path: &mut Prefix,
+    sat: &impl Fn(&Prefix) -> A,
+    unsat: &A,
+    merge: &impl Fn(A, A) -> A,
+  ) -> A {
     match self {
-      Sat => vec![prefix],
-      Unsat => vec![],
+      Sat => sat(path),
+      Unsat => unsat.clone(),
       Mixed(l, r) => {
-        let mut out = l.prefixes_from(prefix.append(B0));
-        out.extend(r.prefixes_from(prefix.append(B1)));
-        out
+        let len = path.len();
+        path.push(B0);
+        let l = l.fold_from(path, sat, unsat, merge);
+        path.truncate(len);
+        path.push(B1);
+        let r = r.fold_from(path, sat, unsat, merge);
+        path.truncate(len);
+        merge(l, r)
       }
     }
   }
-}
 
-#[cfg(test)]
-mod test {
-  use super::*;
+  // keeps only the Sat leaves of the optimized tree whose prefix
+  // satisfies pred
+  pub fn retain(self, pred: impl Fn(&Prefix) -> bool) -> Self {
+    self
+      .optimize()
+      .fold(|p| if pred(p) { Sat } else { Unsat }, Unsat, Self::mixed)
+      .optimize()
+  }
 
-  #[test]
-  fn test_from_range() {
-    let start = Prefix::from(&[0, 0, 0, 1u8][..]);
-    let end = Prefix::from(&[0, 1, 1, 0u8][..]);
+  // calls f in order with each leaf of the optimized tree, whether it
+  // is covered (Sat) or a gap (Unsat). Together the leaves tile the
+  // whole address space of total_bits.
+  pub fn walk_ranges(&self, total_bits: u32, mut f: impl FnMut(bool, Prefix)) {
+    let tree = self.clone().optimize();
+    tree.walk_ranges_from(total_bits, &mut Prefix::empty(), &mut f)
+  }
 
-    let expected = Tree::new()
-      .add([0, 0, 0, 1u8][..].into())
-      .add([0, 0, 1, 0u8][..].into())
-      .add([0, 0, 1, 1u8][..].into())
-      .add([0, 1, 0, 0u8][..].into())
-      .add([0, 1, 0, 1u8][..].into())
-      .add([0, 1, 1, 0u8][..].into())
-      .optimize();
+  fn walk_ranges_from(
+    &self,
+    total_bits: u32,
+    path: &mut Prefix,
+    f: &mut impl FnMut(bool, Prefix),
+  ) {
+    debug_assert!(path.len() <= total_bits as usize);
 
-    let actual = Tree::from_range(&start, &end).unwrap();
+    match self {
+      Sat => f(true, path.clone()),
+      Unsat => f(false, path.clone()),
+      Mixed(l, r) => {
+        let len = path.len();
+        path.push(B0);
+        l.walk_ranges_from(total_bits, path, f);
+        path.truncate(len);
+        path.push(B1);
+        r.walk_ranges_from(total_bits, path, f);
+        path.truncate(len);
+      }
+    }
+  }
+
+  // the first and last address of each maximal run of covered
+  // addresses, as full-width prefixes of total_bits
+  pub fn as_ranges(&self, total_bits: u32) -> Vec<(Prefix, Prefix)> {
+    let mut ranges: Vec<(Prefix, Prefix)> = vec![];
+    self.walk_ranges(total_bits, |covered, prefix| {
+      if !covered {
+        return;
+      }
+
+      let first = prefix.network(total_bits as usize);
+      let last = prefix.broadcast(total_bits as usize);
+
+      match ranges.last_mut() {
+        Some((_, end)) if end.increment().as_ref() == Some(&first) => {
+          *end = last
+        }
+        _ => ranges.push((first, last)),
+      }
+    });
+    ranges
+  }
+
+  // the number of addresses in the set, where the whole space is
+  // total_bits long. Saturates at u128::MAX for the full IPv6 space.
+  pub fn count_addresses(&self, total_bits: u32) -> u128 {
+    self.count_addresses_at(0, total_bits)
+  }
+
+  fn count_addresses_at(&self, depth: u32, total_bits: u32) -> u128 {
+    match self {
+      Sat => Self::block_size(depth, total_bits),
+      Unsat => 0,
+      Mixed(l, r) => l
+        .count_addresses_at(depth + 1, total_bits)
+        .saturating_add(r.count_addresses_at(depth + 1, total_bits)),
+    }
+  }
+
+  // the number of addresses under a prefix depth bits long, saturating
+  // at u128::MAX. A tree deeper than the address has no addresses
+  // down there, so those count as 0.
+  fn block_size(depth: u32, total_bits: u32) -> u128 {
+    match total_bits.checked_sub(depth) {
+      Some(bits) => 1u128.checked_shl(bits).unwrap_or(u128::MAX),
+      None => 0,
+    }
+  }
+
+  // each address of the set in order as a full-width prefix, where
+  // the whole space is total_bits long. Only sane for small sets, as
+  // e.g. a v4 /0 yields over 4 billion addresses.
+  pub fn iter_addresses(
+    &self,
+    total_bits: u32,
+  ) -> impl Iterator<Item = Prefix> + '_ {
+    self.iter_prefixes().flat_map(move |prefix| {
+      let first = prefix.network(total_bits as usize);
+      std::iter::successors(Some(first), Prefix::increment)
+        .take_while(move |addr| prefix.matches(addr))
+    })
+  }
+
+  // the number of addresses covered under each prefix of bucket_len
+  // bits, e.g. each /8 in IPv4, in order. Buckets with nothing
+  // covered are left out.
+  pub fn density_report(
+    &self,
+    bucket_len: u8,
+    total_bits: u32,
+  ) -> Vec<(Prefix, u128)> {
+    let mut report = vec![];
+    // buckets longer than the address hold no addresses
+    if bucket_len as u32 > total_bits {
+      return report;
+    }
+    let mut path = Prefix::empty();
+    self.density_at(&mut path, bucket_len as usize, total_bits, &mut report);
+    report
+  }
+
+  fn density_at(
+    &self,
+    path: &mut Prefix,
+    bucket_len: usize,
+    total_bits: u32,
+    report: &mut Vec<(Prefix, u128)>,
+  ) {
+    let (l, r) = match self {
+      Unsat => return,
+      _ if path.len() == bucket_len => {
+        let count = self.count_addresses_at(path.len() as u32, total_bits);
+        if count > 0 {
+          report.push((path.clone(), count));
+        }
+        return;
+      }
+      // every bucket under it is full
+      Sat => (&Sat, &Sat),
+      Mixed(l, r) => (&**l, &**r),
+    };
+
+    let len = path.len();
+    path.push(B0);
+    l.density_at(path, bucket_len, total_bits, report);
+    path.truncate(len);
+    path.push(B1);
+    r.density_at(path, bucket_len, total_bits, report);
+    path.truncate(len);
+  }
+
+  // the fraction of the set's addresses that are also in other, e.g.
+  // how much of one denylist another one covers, as a reduced
+  // numerator and denominator. None if the set is empty, or if the
+  // denominator doesn't fit in a u128 (a lone /128 out of all of v6).
+  pub fn coverage_fraction_of(
+    &self,
+    other: &Self,
+    total_bits: u32,
+  ) -> Option<(u128, u128)> {
+    let set = self.clone().optimize();
+    let covered = set.intersection_ref(other);
+    // count in blocks of the longest prefix involved rather than in
+    // addresses, so a v6 set doesn't saturate
+    let depth = set
+      .max_prefix_len()?
+      .max(covered.max_prefix_len().unwrap_or(0))
+      .min(total_bits as u8) as u32;
+    let total = match set {
+      Sat => 1u128.checked_shl(depth)?,
+      _ => set.count_addresses(depth),
+    };
+    let covered = covered.count_addresses(depth);
+
+    let (mut a, mut b) = (covered, total);
+    while b != 0 {
+      (a, b) = (b, a % b);
+    }
+    Some((covered / a, total / a))
+  }
+
+  // the n-th (from 0) address of the set in order as a full-width
+  // prefix, or None if the set has no more than n addresses
+  pub fn nth_address(&self, mut n: u128, total_bits: u32) -> Option<Prefix> {
+    let mut path = Prefix::empty();
+    let mut node = self;
+    loop {
+      match node {
+        Sat => {
+          let block = path.clone();
+          path.right_pad(total_bits as usize, B0);
+          return path.checked_add(n).filter(|addr| block.matches(addr));
+        }
+        Unsat => return None,
+        Mixed(l, r) => {
+          let depth = path.len() as u32 + 1;
+          let left = l.count_addresses_at(depth, total_bits);
+          if n < left {
+            path.push(B0);
+            node = l;
+          } else {
+            n -= left;
+            path.push(B1);
+            node = r;
+          }
+        }
+      }
+    }
+  }
+
+  // the prefixes of the optimized set, for use with the ipnet crate.
+  // Fails if the set has prefixes longer than the family's addresses.
+  #[cfg(feature = "ipnet")]
+  pub fn to_ipnet_v4(&self) -> Result<Vec<ipnet::Ipv4Net>> {
+    self.prefixes().into_iter().map(TryFrom::try_from).collect()
+  }
+
+  #[cfg(feature = "ipnet")]
+  pub fn to_ipnet_v6(&self) -> Result<Vec<ipnet::Ipv6Net>> {
+    self.prefixes().into_iter().map(TryFrom::try_from).collect()
+  }
+
+  // n addresses drawn uniformly (with replacement) from the set as
+  // full-width prefixes, none if the set is empty
+  #[cfg(feature = "sample")]
+  pub fn sample(
+    &self,
+    n: usize,
+    total_bits: u32,
+    rng: &mut impl rand::Rng,
+  ) -> Vec<Prefix> {
+    let count = self.count_addresses(total_bits);
+    if count == 0 {
+      return vec![];
+    }
+
+    (0..n)
+      .filter_map(|_| self.nth_address(rng.gen_range(0..count), total_bits))
+      .collect()
+  }
+
+  // the set rounded up to at most max_prefixes prefixes, covering as
+  // few extra addresses as possible, where the whole space is
+  // total_bits long. None if the set isn't empty and max_prefixes is
+  // 0. The cost grows with the size of the tree times max_prefixes
+  // squared, so it's meant for ranges and other small sets.
+  pub fn cover_with_budget(
+    &self,
+    max_prefixes: usize,
+    total_bits: u32,
+  ) -> Option<Self> {
+    let tree = self.clone().optimize();
+    let costs = tree.cover_costs(0, total_bits, max_prefixes);
+    costs.costs[max_prefixes]?;
+    Some(
+      tree
+        .cover_at(&costs, 0, total_bits, max_prefixes)
+        .optimize(),
+    )
+  }
+
+  // the fewest addresses covering the set with at most k prefixes, for
+  // each k up to max, None where it can't be done. the costs of the
+  // subtrees are kept so cover_at doesn't have to work them out again
+  fn cover_costs(&self, depth: u32, total_bits: u32, max: usize) -> CoverCosts {
+    let size = Self::block_size(depth, total_bits);
+    match self {
+      Unsat => CoverCosts {
+        costs: vec![Some(0); max + 1],
+        children: None,
+      },
+      Sat => CoverCosts {
+        costs: (0..=max)
+          .map(|k| if k == 0 { None } else { Some(size) })
+          .collect(),
+        children: None,
+      },
+      Mixed(l, r) => {
+        let l = l.cover_costs(depth + 1, total_bits, max);
+        let r = r.cover_costs(depth + 1, total_bits, max);
+        let costs = (0..=max)
+          .map(|k| {
+            let split = Self::best_split(&l.costs, &r.costs, k).1;
+            match split {
+              Some(split) if k > 0 => Some(split.min(size)),
+              None if k > 0 => Some(size),
+              split => split,
+            }
+          })
+          .collect();
+        CoverCosts {
+          costs,
+          children: Some(Box::new((l, r))),
+        }
+      }
+    }
+  }
+
+  // the number of prefixes for the left side and the cost of the best
+  // way to share k prefixes between the two sides
+  fn best_split(
+    l: &[Option<u128>],
+    r: &[Option<u128>],
+    k: usize,
+  ) -> (usize, Option<u128>) {
+    (0..=k)
+      .map(|kl| {
+        let cost = match (l[kl], r[k - kl]) {
+          (Some(a), Some(b)) => Some(a.saturating_add(b)),
+          _ => None,
+        };
+        (kl, cost)
+      })
+      .min_by_key(|(_, cost)| (cost.is_none(), *cost))
+      .unwrap()
+  }
+
+  fn cover_at(
+    &self,
+    costs: &CoverCosts,
+    depth: u32,
+    total_bits: u32,
+    k: usize,
+  ) -> Self {
+    match (self, &costs.children) {
+      (Mixed(l, r), Some(children)) => {
+        let size = Self::block_size(depth, total_bits);
+        let (l_costs, r_costs) = &**children;
+        let (kl, split) = Self::best_split(&l_costs.costs, &r_costs.costs, k);
+        // a whole block that costs no more is also fewer prefixes
+        if k > 0 && split.is_none_or(|split| size <= split) {
+          return Sat;
+        }
+        Self::mixed(
+          l.cover_at(l_costs, depth + 1, total_bits, kl),
+          r.cover_at(r_costs, depth + 1, total_bits, k - kl),
+        )
+      }
+      (leaf, _) => leaf.clone(),
+    }
+  }
+
+  // same as prefixes().len(), without optimizing the tree or
+  // building the prefixes
+  pub fn count_prefixes(&self) -> usize {
+    self.count_prefixes_full().0
+  }
+
+  // the number of prefixes, and whether the tree is fully Sat
+  fn count_prefixes_full(&self) -> (usize, bool) {
+    match self {
+      Sat => (1, true),
+      Unsat => (0, false),
+      Mixed(l, r) => match (l.count_prefixes_full(), r.count_prefixes_full()) {
+        // would be merged by optimize
+        ((_, true), (_, true)) => (1, true),
+        ((l, _), (r, _)) => (l + r, false),
+      },
+    }
+  }
+
+  // the length of the shortest prefix in the set, None if it's empty.
+  // Like count_prefixes, siblings optimize would merge count as one.
+  pub fn min_prefix_len(&self) -> Option<u8> {
+    self.prefix_len_range(0).0.map(|(min, _)| min)
+  }
+
+  // the length of the longest prefix in the set, None if it's empty
+  pub fn max_prefix_len(&self) -> Option<u8> {
+    self.prefix_len_range(0).0.map(|(_, max)| max)
+  }
+
+  // the min and max length of the prefixes, and whether the tree is
+  // fully Sat
+  fn prefix_len_range(&self, depth: u8) -> (Option<(u8, u8)>, bool) {
+    match self {
+      Sat => (Some((depth, depth)), true),
+      Unsat => (None, false),
+      Mixed(l, r) => {
+        match (l.prefix_len_range(depth + 1), r.prefix_len_range(depth + 1)) {
+          ((_, true), (_, true)) => (Some((depth, depth)), true),
+          ((Some((lmin, lmax)), _), (Some((rmin, rmax)), _)) => {
+            (Some((lmin.min(rmin), lmax.max(rmax))), false)
+          }
+          ((range, _), (None, _)) | ((None, _), (range, _)) => (range, false),
+        }
+      }
+    }
+  }
+
+  // like optimize, but never merges into a prefix shorter than
+  // min_len. Read the result with iter_prefixes(), as prefixes()
+  // optimizes it fully again.
+  pub fn optimize_bounded(self, min_len: u8) -> Self {
+    self.optimize_bounded_at(0, min_len as usize)
+  }
+
+  fn optimize_bounded_at(self, depth: usize, min_len: usize) -> Self {
+    match self {
+      Sat => Sat,
+      Unsat => Unsat,
+      Mixed(l, r) => {
+        let l = l.optimize_bounded_at(depth + 1, min_len);
+        let r = r.optimize_bounded_at(depth + 1, min_len);
+        match (l, r) {
+          (Sat, Sat) if depth >= min_len => Sat,
+          (Unsat, Unsat) => Unsat,
+          (ol, or) => Self::mixed(ol, or),
+        }
+      }
+    }
+  }
+
+  // Lossy: rounds every covered address up to its enclosing prefix of
+  // len bits, e.g. coarsen_to(24) turns any host of a /24 into the
+  // whole /24. Unlike simplify, any coverage at all is enough, so the
+  // result may contain many more addresses than the set.
+  pub fn coarsen_to(self, len: u8) -> Self {
+    self.coarsen_at(0, len as usize).optimize()
+  }
+
+  fn coarsen_at(self, depth: usize, len: usize) -> Self {
+    match self {
+      Mixed(_, _) if depth >= len => Sat,
+      Mixed(l, r) => {
+        Self::mixed(l.coarsen_at(depth + 1, len), r.coarsen_at(depth + 1, len))
+      }
+      leaf => leaf,
+    }
+  }
+
+  /// The minimal list of CIDRs covering exactly the set, in order,
+  /// with adjacent and nested prefixes aggregated. This is the result
+  /// of everything added and deleted.
+  ///
+  /// ```
+  /// use netcalc::{Prefix, Tree};
+  /// use std::net::Ipv4Addr;
+  ///
+  /// let cidr = |a, b, c, d, len| {
+  ///   let mut prefix = Prefix::from(Ipv4Addr::new(a, b, c, d));
+  ///   prefix.truncate(len);
+  ///   prefix
+  /// };
+  /// let tree = Tree::new()
+  ///   .add(cidr(10, 0, 0, 0, 24))
+  ///   .add(cidr(10, 0, 1, 0, 24));
+  /// assert_eq!(tree.summarize(), vec![cidr(10, 0, 0, 0, 23)]);
+  /// ```
+  pub fn summarize(&self) -> Vec<Prefix> {
+    self.prefixes()
+  }
+
+  pub fn prefixes(&self) -> Vec<Prefix> {
+    self.clone().optimize().iter_prefixes().collect()
+  }
+
+  // like iter_prefixes, along with the depth (prefix length) of each
+  // leaf
+  pub fn leaves_with_depth(&self) -> impl Iterator<Item = (Prefix, u8)> + '_ {
+    self.iter_prefixes().map(|p| {
+      let depth = p.len() as u8;
+      (p, depth)
+    })
+  }
+
+  // lazily yields the prefix of each Sat leaf in order. Unlike
+  // prefixes(), the tree is not optimized first.
+  pub fn iter_prefixes(&self) -> Prefixes<'_> {
+    Prefixes {
+      path: Prefix::empty(),
+      stack: vec![(self, 0, None)],
+    }
+  }
+}
+
+pub struct Prefixes<'a> {
+  // the prefix of the last visited node
+  path: Prefix,
+  // nodes to visit, with the length of their parent's prefix and the
+  // bit leading to them
+  stack: Vec<(&'a Tree, usize, Option<Bit>)>,
+}
+
+impl<'a> Iterator for Prefixes<'a> {
+  type Item = Prefix;
+
+  fn next(&mut self) -> Option<Prefix> {
+    while let Some((tree, depth, bit)) = self.stack.pop() {
+      self.path.truncate(depth);
+      if let Some(bit) = bit {
+        self.path.push(bit);
+      }
+
+      match tree {
+        Sat => return Some(self.path.clone()),
+        Unsat => continue,
+        Mixed(l, r) => {
+          // right goes first so the left is popped first
+          let depth = self.path.len();
+          self.stack.push((r, depth, Some(B1)));
+          self.stack.push((l, depth, Some(B0)));
+        }
+      }
+    }
+    None
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_from_range() {
+    let start = Prefix::from(&[0, 0, 0, 1u8][..]);
+    let end = Prefix::from(&[0, 1, 1, 0u8][..]);
+
+    let expected = Tree::new()
+      .add([0, 0, 0, 1u8][..].into())
+      .add([0, 0, 1, 0u8][..].into())
+      .add([0, 0, 1, 1u8][..].into())
+      .add([0, 1, 0, 0u8][..].into())
+      .add([0, 1, 0, 1u8][..].into())
+      .add([0, 1, 1, 0u8][..].into())
+      .optimize();
+
+    let actual = Tree::from_range(&start, &end).unwrap();
 
     assert_eq!(expected, actual);
   }
 
+  #[test]
+  fn test_from_range_fast_paths() {
+    let zeros = prefix_from_octets(&[0; 4]);
+    let ones = prefix_from_octets(&[255; 4]);
+    let tree = Tree::from_range(&zeros, &ones).unwrap();
+    assert_eq!(tree, Sat);
+    assert_eq!(tree.prefixes(), vec![Prefix::empty()]);
+
+    let addr = prefix_from_octets(&[10, 0, 0, 1]);
+    let tree = Tree::from_range(&addr, &addr).unwrap();
+    assert_eq!(tree.prefixes(), vec![addr]);
+  }
+
+  #[test]
+  fn test_from_range_exclusive() {
+    let start = prefix_from_octets(&[10, 0, 0, 0]);
+    let end = prefix_from_octets(&[10, 0, 1, 0]);
+    let block = cidr(&[10, 0, 0, 0], 24);
+
+    // the end is excluded, unlike in from_range
+    let exclusive = Tree::from_range_exclusive(&start, &end).unwrap();
+    assert_eq!(exclusive.prefixes(), vec![block.clone()]);
+    let inclusive = Tree::from_range(&start, &end).unwrap();
+    assert_eq!(inclusive.prefixes(), vec![block, end.clone()]);
+
+    assert_eq!(Tree::from_range_exclusive(&end, &end).unwrap(), Unsat);
+    let zeros = prefix_from_octets(&[0; 4]);
+    assert_eq!(Tree::from_range_exclusive(&zeros, &zeros).unwrap(), Unsat);
+    assert!(Tree::from_range_exclusive(&end, &start).is_err());
+  }
+
   #[test]
   fn test_chunk() {
     let mut prefix = Prefix::from_u8(1);
@@ -315,4 +1603,1082 @@ mod test {
 
     assert_eq!(prefix.chunks(8).unwrap(), vec![1, 2, 3, 4]);
   }
+
+  #[test]
+  fn test_difference_with_report() {
+    let a = Tree::new()
+      .add([0, 0u8][..].into())
+      .add([0, 1, 1u8][..].into())
+      .add([1, 0, 1, 0u8][..].into());
+    let b = Tree::new()
+      .add([0, 0, 1u8][..].into())
+      .add([0, 1u8][..].into())
+      .add([1, 1u8][..].into());
+
+    let (kept, removed) = a.clone().difference_with_report(b.clone());
+
+    assert_eq!(kept, a.clone().difference(b.clone()));
+    assert_eq!(removed, a.clone().intersection(b));
+    assert_eq!(kept.union(removed), a.optimize());
+  }
+
+  #[test]
+  fn test_contains_addr() {
+    let v4 = Tree::new().add(Prefix::from(Ipv4Addr::new(10, 0, 0, 0)));
+    let v4 = v4.add(cidr(&[192, 168, 0, 0], 16));
+    assert!(v4.contains_ipv4(Ipv4Addr::new(10, 0, 0, 0)));
+    assert!(v4.contains_ipv4(Ipv4Addr::new(192, 168, 42, 1)));
+    assert!(!v4.contains_ipv4(Ipv4Addr::new(10, 0, 0, 1)));
+    assert!(!v4.contains_ipv4(Ipv4Addr::new(192, 169, 0, 0)));
+
+    let addr: Ipv6Addr = "2001:db8::1".parse().unwrap();
+    let mut net = Prefix::from(addr);
+    net.truncate(32);
+    let v6 = Tree::new().add(net);
+    assert!(v6.contains_ipv6(addr));
+    assert!(!v6.contains_ipv6("2001:db9::1".parse().unwrap()));
+  }
+
+  #[test]
+  fn test_partition() {
+    let tree = Tree::new()
+      .add([0, 0u8][..].into())
+      .add([0, 1, 1u8][..].into())
+      .add([1, 0, 1u8][..].into());
+
+    let (inside, outside) = tree.clone().partition(&[0, 1u8][..].into());
+
+    assert_eq!(inside, Tree::new().add([0, 1, 1u8][..].into()));
+    assert_eq!(inside.clone().intersection(outside.clone()), Unsat);
+    assert_eq!(inside.union(outside), tree.optimize());
+  }
+
+  #[test]
+  fn test_as_ranges() {
+    let a = cidr(&[10, 0, 0, 0], 24);
+    let b = cidr(&[10, 0, 1, 0], 24);
+    let c = cidr(&[10, 0, 3, 0], 24);
+
+    // the two adjacent /24s merge into one range, 10.0.3.0/24 doesn't
+    let tree = Tree::new().add(a).add(b).add(c);
+    assert_eq!(
+      tree.as_ranges(32),
+      vec![
+        (
+          prefix_from_octets(&[10, 0, 0, 0]),
+          prefix_from_octets(&[10, 0, 1, 255])
+        ),
+        (
+          prefix_from_octets(&[10, 0, 3, 0]),
+          prefix_from_octets(&[10, 0, 3, 255])
+        ),
+      ]
+    );
+
+    // runs crossing a subtree boundary are merged as well
+    let tree = Tree::from_range(
+      &prefix_from_octets(&[10, 0, 0, 255]),
+      &prefix_from_octets(&[10, 0, 1, 0]),
+    )
+    .unwrap();
+    assert_eq!(tree.as_ranges(32).len(), 1);
+
+    // the two /25s are in different /24s, so only the running end of
+    // the previous range, not the tree structure, tells they touch
+    let a = cidr(&[10, 0, 0, 128], 25);
+    let b = cidr(&[10, 0, 1, 0], 25);
+    let tree = Tree::new().add(a).add(b);
+    assert_eq!(tree.prefixes().len(), 2);
+    assert_eq!(
+      tree.as_ranges(32),
+      vec![(
+        prefix_from_octets(&[10, 0, 0, 128]),
+        prefix_from_octets(&[10, 0, 1, 127])
+      )]
+    );
+
+    // sibling /25s make one range whether or not they've been merged
+    let a = cidr(&[10, 0, 0, 0], 25);
+    let b = cidr(&[10, 0, 0, 128], 25);
+    assert_eq!(
+      Tree::new().add(a).add(b).as_ranges(32),
+      vec![(
+        prefix_from_octets(&[10, 0, 0, 0]),
+        prefix_from_octets(&[10, 0, 0, 255])
+      )]
+    );
+
+    assert!(Tree::new().as_ranges(32).is_empty());
+    assert_eq!(
+      Tree::new().flip().as_ranges(2),
+      vec![([0, 0u8][..].into(), [1, 1u8][..].into())]
+    );
+  }
+
+  #[test]
+  fn test_walk_ranges() {
+    let tree = Tree::new()
+      .add([0, 1u8][..].into())
+      .add([1, 0, 0u8][..].into())
+      .add([1, 0, 1u8][..].into());
+
+    let mut leaves = vec![];
+    tree.walk_ranges(3, |covered, prefix| leaves.push((covered, prefix)));
+
+    assert_eq!(
+      leaves,
+      vec![
+        (false, [0, 0u8][..].into()),
+        (true, [0, 1u8][..].into()),
+        (true, [1, 0u8][..].into()),
+        (false, [1, 1u8][..].into()),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_complement_within() {
+    let universe: Prefix = [1, 0u8][..].into();
+    let used = Tree::new()
+      .add([0, 1u8][..].into())
+      .add([1, 0, 0, 1u8][..].into())
+      .add([1, 0, 1, 1, 0u8][..].into());
+
+    let free = used.clone().complement_within(&universe);
+    let used_within = used.partition(&universe).0;
+
+    assert_eq!(free.clone().intersection(used_within.clone()), Unsat);
+    assert_eq!(free.union(used_within), Tree::new().add(universe));
+  }
+
+  #[test]
+  fn test_to_bitmap() {
+    let universe = cidr(&[10, 0, 0, 16], 28);
+    let quarter = cidr(&[10, 0, 0, 20], 30);
+    let tree = Tree::new()
+      .add(quarter)
+      .add(prefix_from_octets(&[10, 0, 0, 31]))
+      .add(prefix_from_octets(&[10, 0, 0, 32]));
+
+    let bitmap = tree.to_bitmap(&universe, 32).unwrap();
+    let covered = (0..16).filter(|i| bitmap[*i]).collect::<Vec<_>>();
+    assert_eq!(bitmap.len(), 16);
+    assert_eq!(covered, vec![4, 5, 6, 7, 15]);
+
+    // more than 2^24 addresses
+    let slash7 = cidr(&[10, 0, 0, 16], 7);
+    assert!(tree.to_bitmap(&slash7, 32).is_err());
+    assert_eq!(
+      tree
+        .to_bitmap(&prefix_from_octets(&[10, 0, 0, 31]), 32)
+        .unwrap(),
+      vec![true]
+    );
+
+    // the part of a tree deeper than the address isn't in the bitmap
+    let deep = Tree::new()
+      .add([0, 1u8][..].into())
+      .add([1, 1, 0u8][..].into());
+    let bitmap = deep.to_bitmap(&Prefix::empty(), 2).unwrap();
+    assert_eq!(bitmap, vec![false, true, false, false]);
+  }
+
+  #[test]
+  fn test_alternating() {
+    let universe = cidr(&[10, 0, 0, 0], 24);
+    let tree = Tree::alternating(&universe, 32).unwrap();
+
+    assert_eq!(tree.clone().optimize(), tree);
+    let prefixes = tree.prefixes();
+    assert_eq!(prefixes.len(), 128);
+    assert_eq!(prefixes[0], prefix_from_octets(&[10, 0, 0, 0]));
+    assert_eq!(prefixes[127], prefix_from_octets(&[10, 0, 0, 254]));
+
+    assert_eq!(
+      Tree::alternating(&universe, 24).unwrap().prefixes(),
+      vec![universe.clone()]
+    );
+    assert!(Tree::alternating(&universe, 16).is_err());
+  }
+
+  #[test]
+  fn test_cover_with_budget() {
+    // 10.0.0.1-10.0.0.6 is exactly /32, /31, /31, /32
+    let tree = Tree::from_range(
+      &prefix_from_octets(&[10, 0, 0, 1]),
+      &prefix_from_octets(&[10, 0, 0, 6]),
+    )
+    .unwrap();
+    assert_eq!(tree.cover_with_budget(4, 32), Some(tree.clone().optimize()));
+
+    // 1 extra address with three prefixes, rounding either end
+    let three = tree.cover_with_budget(3, 32).unwrap();
+    assert!(three.contains_tree(&tree));
+    assert_eq!(three.prefixes().len(), 3);
+    assert_eq!(three.count_addresses(32), 7);
+
+    // two prefixes can't do better than the whole /29, which is fewer
+    let block = cidr(&[10, 0, 0, 0], 29);
+    for budget in [1, 2] {
+      assert_eq!(
+        tree.cover_with_budget(budget, 32).unwrap().prefixes(),
+        vec![block.clone()]
+      );
+    }
+
+    assert_eq!(tree.cover_with_budget(0, 32), None);
+    assert_eq!(Tree::new().cover_with_budget(0, 32), Some(Tree::new()));
+  }
+
+  #[test]
+  fn test_iter_addresses() {
+    let a = cidr(&[10, 0, 0, 4], 31);
+    let b = prefix_from_octets(&[10, 0, 0, 1]);
+    let tree = Tree::new().add(a).add(b);
+    assert_eq!(
+      tree.iter_addresses(32).collect::<Vec<_>>(),
+      vec![
+        prefix_from_octets(&[10, 0, 0, 1]),
+        prefix_from_octets(&[10, 0, 0, 4]),
+        prefix_from_octets(&[10, 0, 0, 5]),
+      ]
+    );
+
+    // the last block of the space stops without wrapping around
+    let tree = Tree::new().flip();
+    let addrs = tree.iter_addresses(2).collect::<Vec<_>>();
+    assert_eq!(addrs.len(), 4);
+    assert_eq!(addrs[3], [1, 1u8][..].into());
+    assert_eq!(Tree::new().iter_addresses(32).count(), 0);
+  }
+
+  #[test]
+  fn test_density_report() {
+    let a = cidr(&[10, 0, 0, 0], 7);
+    let b = cidr(&[12, 0, 0, 0], 16);
+    let c = cidr(&[13, 128, 0, 0], 9);
+    let tree = Tree::new().add(a).add(b).add(c);
+
+    let report = tree
+      .density_report(8, 32)
+      .into_iter()
+      .map(|(bucket, count)| (bucket.to_u64().unwrap(), count))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      report,
+      vec![(10, 1 << 24), (11, 1 << 24), (12, 1 << 16), (13, 1 << 23)]
+    );
+
+    assert!(Tree::new().density_report(8, 32).is_empty());
+    assert_eq!(Tree::new().flip().density_report(16, 128).len(), 1 << 16);
+
+    // a tree or buckets deeper than the address
+    let deep = Tree::new()
+      .add([0, 1u8][..].into())
+      .add([1, 1, 0u8][..].into());
+    let report = deep
+      .density_report(1, 2)
+      .into_iter()
+      .map(|(bucket, count)| (bucket.to_u64().unwrap(), count))
+      .collect::<Vec<_>>();
+    assert_eq!(report, vec![(0, 1)]);
+    assert!(deep.density_report(3, 2).is_empty());
+  }
+
+  #[test]
+  fn test_prefix_len_range() {
+    let tree = Tree::new()
+      .add(cidr(&[10, 0, 0, 0], 8))
+      .add(cidr(&[192, 168, 1, 7], 32))
+      .add(cidr(&[172, 16, 0, 0], 13))
+      .add(cidr(&[172, 24, 0, 0], 13));
+    assert_eq!(tree.min_prefix_len(), Some(8));
+    assert_eq!(tree.max_prefix_len(), Some(32));
+
+    // the two /13s merge into a /12
+    let tree = tree.del(cidr(&[10, 0, 0, 0], 8));
+    assert_eq!(tree.min_prefix_len(), Some(12));
+
+    assert_eq!(Tree::new().min_prefix_len(), None);
+    assert_eq!(Tree::new().max_prefix_len(), None);
+    assert_eq!(Tree::new().flip().max_prefix_len(), Some(0));
+  }
+
+  #[test]
+  fn test_coverage_fraction_of() {
+    let a = Tree::new()
+      .add(cidr(&[10, 0, 0, 0], 24))
+      .add(cidr(&[10, 0, 1, 0], 26));
+    let b = Tree::new().add(cidr(&[10, 0, 0, 0], 16));
+
+    // a is within b
+    assert_eq!(a.coverage_fraction_of(&b, 32), Some((1, 1)));
+    // 320 of the 65536 addresses of b are in a
+    assert_eq!(b.coverage_fraction_of(&a, 32), Some((5, 1024)));
+
+    let c = Tree::new().add(cidr(&[10, 0, 0, 128], 25));
+    assert_eq!(a.coverage_fraction_of(&c, 32), Some((2, 5)));
+    assert_eq!(a.coverage_fraction_of(&Tree::new(), 32), Some((0, 1)));
+    assert_eq!(Tree::new().coverage_fraction_of(&a, 32), None);
+
+    let half = Tree::new().add([0u8][..].into());
+    assert_eq!(
+      half.coverage_fraction_of(&Tree::new().flip(), 128),
+      Some((1, 1))
+    );
+    assert_eq!(
+      Tree::new().flip().coverage_fraction_of(&half, 128),
+      Some((1, 2))
+    );
+    // 2^-128 can't be written with a u128 denominator
+    let host = Tree::new().add(Prefix::from(&[0u8; 128][..]));
+    assert_eq!(Tree::new().flip().coverage_fraction_of(&host, 128), None);
+    let rest = Tree::new().flip().difference(host.clone());
+    assert_eq!(rest.coverage_fraction_of(&host, 128), Some((0, 1)));
+    assert_eq!(
+      rest.coverage_fraction_of(&Tree::new().flip(), 128),
+      Some((1, 1))
+    );
+  }
+
+  #[test]
+  fn test_carve() {
+    let universe = cidr(&[10, 0, 0, 0], 24);
+
+    // 10.0.0.64/26 is taken
+    let mut tree = Tree::new().add(cidr(&[10, 0, 0, 64], 26));
+    let mut carved = vec![];
+    while let Some((block, rest)) = tree.clone().carve(&universe, 26) {
+      carved.push(block);
+      tree = rest;
+    }
+    assert_eq!(
+      carved,
+      vec![
+        cidr(&[10, 0, 0, 0], 26),
+        cidr(&[10, 0, 0, 128], 26),
+        cidr(&[10, 0, 0, 192], 26)
+      ]
+    );
+    assert!(tree.clone().optimize().contains(&universe));
+
+    // a partially used block isn't free
+    let tree = Tree::new().add(cidr(&[10, 0, 0, 5], 32));
+    assert_eq!(
+      tree.clone().carve(&universe, 25).unwrap().0,
+      cidr(&[10, 0, 0, 128], 25)
+    );
+    assert_eq!(
+      tree.clone().carve(&universe, 32).unwrap().0,
+      cidr(&[10, 0, 0, 0], 32)
+    );
+    assert!(tree.carve(&universe, 16).is_none());
+  }
+
+  #[test]
+  fn test_complement_in_bounds() {
+    let tree = Tree::new()
+      .add(cidr(&[10, 0, 0, 0], 24))
+      .add(cidr(&[10, 0, 3, 0], 24));
+
+    assert_eq!(tree.covering_prefix(), Some(cidr(&[10, 0, 0, 0], 22)));
+    assert_eq!(
+      tree.complement_in_bounds().prefixes(),
+      vec![cidr(&[10, 0, 1, 0], 24), cidr(&[10, 0, 2, 0], 24)]
+    );
+
+    assert_eq!(Tree::new().covering_prefix(), None);
+    assert!(Tree::new().complement_in_bounds().is_empty());
+    let single = Tree::new().add(cidr(&[10, 0, 1, 0], 24));
+    assert_eq!(single.covering_prefix(), Some(cidr(&[10, 0, 1, 0], 24)));
+    assert!(single.complement_in_bounds().is_empty());
+  }
+
+  #[test]
+  fn test_rebase() {
+    let from = cidr(&[10, 0, 0, 0], 16);
+    let to = cidr(&[192, 168, 0, 0], 16);
+
+    let tree = Tree::new()
+      .add(prefix_from_octets(&[10, 0, 1, 1]))
+      .add(prefix_from_octets(&[10, 0, 200, 5]))
+      .add(prefix_from_octets(&[10, 1, 0, 1]));
+
+    let expected = Tree::new()
+      .add(prefix_from_octets(&[192, 168, 1, 1]))
+      .add(prefix_from_octets(&[192, 168, 200, 5]));
+
+    assert_eq!(tree.rebase(&from, &to).unwrap(), expected);
+    assert!(tree.rebase(&from, &Prefix::empty()).is_err());
+
+    // a set covering the whole source covers the whole destination
+    let tree = Tree::new().add(Prefix::empty());
+    assert_eq!(tree.rebase(&from, &to).unwrap(), Tree::new().add(to));
+  }
+
+  #[test]
+  fn test_overlaps() {
+    let a = Tree::new().add([0, 1u8][..].into());
+    let b = Tree::new()
+      .add([0, 0u8][..].into())
+      .add([0, 1, 1, 0u8][..].into());
+    let c = Tree::new().add([0, 0, 1u8][..].into());
+
+    assert!(a.overlaps(&b));
+    assert!(!a.overlaps(&c));
+    assert!(Tree::new().add(Prefix::empty()).overlaps(&c));
+    assert!(!Tree::new().overlaps(&c));
+  }
+
+  #[test]
+  fn test_coarsen_to() {
+    let tree = [1, 7, 200].iter().fold(Tree::new(), |t, h| {
+      t.add(prefix_from_octets(&[10, 0, 5, *h]))
+    });
+    let net = cidr(&[10, 0, 5, 0], 24);
+    assert_eq!(tree.coarsen_to(24).prefixes(), vec![net.clone()]);
+
+    // prefixes already as short as len are kept as they are
+    let slash16 = cidr(&[10, 1, 0, 0], 16);
+    let tree = Tree::new().add(slash16).add(net);
+    assert_eq!(tree.clone().coarsen_to(24), tree.clone().optimize());
+    assert_eq!(tree.coarsen_to(8).prefixes().len(), 1);
+  }
+
+  #[test]
+  fn test_pretty() {
+    let tree = Tree::mixed(Sat, Tree::mixed(Unsat, Sat));
+    assert_eq!(
+      tree.pretty(),
+      "(root) Mixed\n  0 Sat\n  1 Mixed\n    10 Unsat\n    11 Sat\n"
+    );
+    assert_eq!(Tree::new().pretty(), "(root) Unsat\n");
+  }
+
+  #[test]
+  fn test_coverage_eq() {
+    // 10.0.0.0 - 10.0.2.255, i.e. 10.0.0.0/23 and 10.0.2.0/24
+    let start = prefix_from_octets(&[10, 0, 0, 0]);
+    let end = prefix_from_octets(&[10, 0, 2, 255]);
+    let range = Tree::from_range(&start, &end).unwrap();
+
+    let cidrs = Tree::new()
+      .add(cidr(&[10, 0, 0, 0], 23))
+      .add(cidr(&[10, 0, 2, 0], 24));
+    let singles = (0..3)
+      .map(|n| cidr(&[10, 0, n, 0], 24))
+      .fold(Tree::new(), Tree::add);
+
+    assert!(range.coverage_eq(&cidrs));
+    assert!(cidrs.coverage_eq(&singles));
+    assert!(range.coverage_eq(&singles));
+    assert!(!range.coverage_eq(&Tree::new().add(cidr(&[10, 0, 0, 0], 24))));
+
+    // different nodes, same coverage
+    assert_ne!(Tree::mixed(Sat, Sat), Sat);
+    assert!(Tree::mixed(Sat, Sat).coverage_eq(&Sat));
+  }
+
+  #[test]
+  fn test_contains_tree() {
+    let slash8 = cidr(&[10, 0, 0, 0], 8);
+    let slash16 = cidr(&[10, 1, 0, 0], 16);
+    let outside = cidr(&[11, 0, 0, 0], 16);
+
+    let a = Tree::new().add(slash8);
+    let b = Tree::new().add(slash16.clone());
+    assert!(a.contains_tree(&b));
+    assert!(!b.contains_tree(&a));
+    assert!(!a.contains_tree(&b.clone().add(outside)));
+    assert!(a.contains_tree(&Tree::new()));
+
+    // an unoptimized tree covering everything still contains Sat
+    let full = Tree::mixed(Tree::mixed(Sat, Sat), Sat);
+    assert!(full.contains_tree(&Tree::new().add(Prefix::empty())));
+  }
+
+  #[test]
+  fn test_count_addresses() {
+    let tree = Tree::new()
+      .add([0, 1u8][..].into())
+      .add([1, 1, 0u8][..].into());
+    assert_eq!(tree.count_addresses(3), 3);
+    assert_eq!(Tree::new().count_addresses(32), 0);
+    assert_eq!(Tree::new().flip().count_addresses(32), 1 << 32);
+    assert_eq!(Tree::new().flip().count_addresses(128), u128::MAX);
+    // the part deeper than the address has no addresses
+    assert_eq!(tree.count_addresses(2), 1);
+    assert_eq!(tree.count_addresses(0), 0);
+
+    let addrs = (0..4).map(|n| tree.nth_address(n, 3)).collect::<Vec<_>>();
+    assert_eq!(
+      addrs,
+      vec![
+        Some([0, 1, 0u8][..].into()),
+        Some([0, 1, 1u8][..].into()),
+        Some([1, 1, 0u8][..].into()),
+        None,
+      ]
+    );
+  }
+
+  #[cfg(feature = "ipnet")]
+  #[test]
+  fn test_ipnet() {
+    use ipnet::{Ipv4Net, Ipv6Net};
+
+    let nets: Vec<Ipv4Net> = vec![
+      "10.0.0.0/24".parse().unwrap(),
+      "10.0.1.0/24".parse().unwrap(),
+      "192.168.1.7/32".parse().unwrap(),
+    ];
+    let tree = Tree::from_prefixes(nets.into_iter().map(Prefix::from));
+    assert_eq!(
+      tree.to_ipnet_v4().unwrap(),
+      vec![
+        "10.0.0.0/23".parse::<Ipv4Net>().unwrap(),
+        "192.168.1.7/32".parse().unwrap()
+      ]
+    );
+
+    for net in ["2001:db8::/32", "::/0", "fe80::1/128"] {
+      let net: Ipv6Net = net.parse().unwrap();
+      let back = Ipv6Net::try_from(Prefix::from(net)).unwrap();
+      assert_eq!(back, net);
+    }
+    let net: Ipv4Net = "0.0.0.0/0".parse().unwrap();
+    assert_eq!(Ipv4Net::try_from(Prefix::from(net)).unwrap(), net);
+
+    // host bits are dropped on the way in
+    let net: Ipv4Net = "10.0.0.5/24".parse().unwrap();
+    assert_eq!(Prefix::from(net), Prefix::from(net.trunc()));
+
+    let long = Prefix::from(Ipv6Addr::LOCALHOST);
+    assert!(Ipv4Net::try_from(long).is_err());
+    assert!(Tree::new().flip().to_ipnet_v6().is_ok());
+  }
+
+  #[cfg(feature = "sample")]
+  #[test]
+  fn test_sample() {
+    use rand::SeedableRng;
+
+    let slash24 = cidr(&[10, 0, 5, 0], 24);
+    let tree = Tree::new().add(slash24.clone());
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    let addrs = tree.sample(100, 32, &mut rng);
+    assert_eq!(addrs.len(), 100);
+    assert!(addrs.iter().all(|a| a.len() == 32 && slash24.matches(a)));
+    assert!(Tree::new().sample(10, 32, &mut rng).is_empty());
+  }
+
+  #[test]
+  fn test_count_prefixes() {
+    let trees = [
+      Tree::new(),
+      Tree::new().add(Prefix::empty()),
+      // not optimized
+      Tree::mixed(Tree::mixed(Sat, Sat), Tree::mixed(Sat, Sat)),
+      Tree::new()
+        .add([0, 0, 1u8][..].into())
+        .add([0, 1u8][..].into())
+        .add([1, 1, 0, 1u8][..].into()),
+      Tree::from_range(&[0, 0, 0, 1u8][..].into(), &[1, 1, 1, 0u8][..].into())
+        .unwrap(),
+    ];
+
+    for tree in trees {
+      assert_eq!(tree.count_prefixes(), tree.prefixes().len());
+    }
+  }
+
+  #[test]
+  fn test_simplify() {
+    // 3/4 of 01 and 1/4 of 10 are covered
+    let tree = Tree::new()
+      .add([0, 1, 0u8][..].into())
+      .add([0, 1, 1, 0u8][..].into())
+      .add([1, 0, 0, 0u8][..].into());
+
+    assert_eq!(
+      tree.clone().simplify(0.75, 4),
+      Tree::new()
+        .add([0, 1u8][..].into())
+        .add([1, 0, 0, 0u8][..].into())
+    );
+    assert_eq!(tree.clone().simplify(0.8, 4), tree.clone().optimize());
+    assert_eq!(tree.clone().simplify(0.0, 4), Sat);
+    // lossy, but never drops addresses
+    let simplified = tree.clone().simplify(0.5, 4);
+    assert_eq!(tree.clone().difference(simplified), Unsat);
+
+    // in a 3-bit space the 4-bit leaves hold no addresses, so 01 is
+    // only half filled
+    assert_eq!(
+      tree.clone().simplify(0.75, 3),
+      Tree::new()
+        .add([0, 1, 0u8][..].into())
+        .add([0, 1, 1, 0u8][..].into())
+        .add([1, 0, 0, 0u8][..].into())
+    );
+  }
+
+  #[test]
+  fn test_optimize_bounded() {
+    let a = cidr(&[10, 0, 0, 0], 8);
+    let b = cidr(&[11, 0, 0, 0], 8);
+    let tree = Tree::new().add(a.clone()).add(b.clone());
+
+    assert_eq!(tree.clone().optimize().iter_prefixes().count(), 1);
+
+    let bounded = tree.clone().optimize_bounded(8);
+    assert_eq!(bounded.iter_prefixes().collect::<Vec<_>>(), vec![a, b]);
+
+    assert_eq!(tree.clone().optimize_bounded(7), tree.optimize());
+  }
+
+  #[test]
+  fn test_nearest() {
+    let a: Prefix = [0, 1, 1, 0u8][..].into();
+    let b: Prefix = [1, 1, 0, 0u8][..].into();
+    assert_eq!(a.hamming_distance(&b), Some(2));
+    assert_eq!(a.hamming_distance(&a), Some(0));
+    assert_eq!(a.hamming_distance(&Prefix::empty()), None);
+
+    let tree = Tree::new()
+      .add([0, 0, 0, 0u8][..].into())
+      .add([1, 1u8][..].into());
+    // 1 bit off from 0000, but 2 bits off from any address in 11
+    assert_eq!(
+      tree.nearest(&[0, 1, 0, 0u8][..].into()),
+      Some([0, 0, 0, 0u8][..].into())
+    );
+    assert_eq!(
+      tree.nearest(&[1, 0, 1, 1u8][..].into()),
+      Some([1, 1u8][..].into())
+    );
+    assert_eq!(Tree::new().nearest(&a), None);
+  }
+
+  #[test]
+  fn test_tree_ord() {
+    use std::collections::BTreeSet;
+
+    let a = Tree::new().add([0u8][..].into());
+    let b = Tree::new().add([1u8][..].into());
+    let c = Tree::new().add([0, 1u8][..].into());
+
+    assert!(Unsat < a && a < Sat);
+    // Mixed(Sat, Unsat) > Mixed(Mixed(..), Unsat) > Mixed(Unsat, Sat)
+    assert!(b < c && c < a);
+
+    let trees: BTreeSet<_> = [
+      a.clone(),
+      Tree::new()
+        .add([0, 0u8][..].into())
+        .add([0, 1u8][..].into()),
+      b.clone(),
+      Sat,
+    ]
+    .into_iter()
+    .map(Tree::optimize)
+    .collect();
+    assert_eq!(trees.into_iter().collect::<Vec<_>>(), vec![b, a, Sat]);
+  }
+
+  #[test]
+  fn test_gaps() {
+    let universe = cidr(&[10, 0, 0, 0], 16);
+
+    let used = Tree::new()
+      .add(cidr(&[10, 0, 0, 0], 24))
+      .add(cidr(&[10, 0, 2, 0], 23))
+      .add(cidr(&[10, 0, 128, 0], 17));
+    // free: 10.0.1.0/24, 10.0.4.0/22, 10.0.8.0/21, 10.0.16.0/20,
+    // 10.0.32.0/19, 10.0.64.0/18
+    assert_eq!(used.count_gaps(&universe), 6);
+    assert_eq!(
+      used.largest_free_block(&universe),
+      Some(cidr(&[10, 0, 64, 0], 18))
+    );
+
+    let full = Tree::new().add(universe.clone());
+    assert_eq!(full.count_gaps(&universe), 0);
+    assert_eq!(full.largest_free_block(&universe), None);
+    assert_eq!(Tree::new().largest_free_block(&universe), Some(universe));
+  }
+
+  #[test]
+  fn test_retain() {
+    let tree = [1, 2, 4, 7]
+      .iter()
+      .fold(Tree::new(), |t, n| t.add(cidr(&[10, 0, *n, 0], 24)));
+
+    let even_third = |p: &Prefix| {
+      let octets = p.network(32).chunks(8).unwrap();
+      octets[2] & 1 == 0
+    };
+    assert_eq!(
+      tree.retain(even_third).prefixes(),
+      vec![cidr(&[10, 0, 2, 0], 24), cidr(&[10, 0, 4, 0], 24)]
+    );
+    assert!(Tree::new().retain(|_| true).is_empty());
+  }
+
+  #[test]
+  fn test_leaves_with_depth() {
+    let tree = Tree::new()
+      .add([0, 0, 1u8][..].into())
+      .add([1u8][..].into())
+      .add([0, 1, 1, 0u8][..].into());
+
+    let leaves = tree.leaves_with_depth().collect::<Vec<_>>();
+    assert_eq!(leaves.len(), 3);
+    assert!(leaves.iter().all(|(p, depth)| p.len() == *depth as usize));
+    assert_eq!(
+      leaves.into_iter().map(|(_, d)| d).collect::<Vec<_>>(),
+      vec![3, 4, 1]
+    );
+  }
+
+  #[test]
+  fn test_fold() {
+    let tree = Tree::new()
+      .add([0, 0, 1u8][..].into())
+      .add([0, 1u8][..].into())
+      .add([1, 1, 0, 1u8][..].into())
+      .optimize();
+
+    let leaves = tree.fold(|_| 1, 0, |a, b| a + b);
+    assert_eq!(leaves, tree.prefixes().len());
+
+    let longest = tree.fold(|p| p.len(), 0, usize::max);
+    assert_eq!(longest, 4);
+  }
+
+  // xorshift, good enough for generating test cases
+  struct Rng(u64);
+
+  impl Rng {
+    fn next(&mut self) -> u64 {
+      self.0 ^= self.0 << 13;
+      self.0 ^= self.0 >> 7;
+      self.0 ^= self.0 << 17;
+      self.0
+    }
+  }
+
+  #[test]
+  fn test_from_prefixes() {
+    let mut rng = Rng(0x2545f4914f6cdd1d);
+    let prefixes = (0..10000)
+      .map(|_| {
+        let n = rng.next();
+        let mut p = prefix_from_octets(&(n as u32 & 0x00ff_ffff).to_be_bytes());
+        p.truncate(16 + (n >> 32) as usize % 17);
+        p
+      })
+      .collect::<Vec<_>>();
+
+    // the same set as merging the prefixes with union, which doesn't
+    // share any code with insert
+    let single = |p: &Prefix| {
+      p.as_slice().iter().rev().fold(Sat, |t, bit| match bit {
+        B0 => Tree::mixed(t, Unsat),
+        B1 => Tree::mixed(Unsat, t),
+      })
+    };
+    let merged = Tree::merge_all(prefixes.iter().map(single).collect());
+    let built = Tree::from_prefixes(prefixes.clone());
+    assert_eq!(built, merged.optimize());
+    assert_eq!(
+      prefixes.into_iter().fold(Tree::new(), Tree::add).optimize(),
+      built
+    );
+
+    // inserting under an existing Sat is a no-op
+    let mut tree = Tree::new().flip();
+    tree.insert(&prefix_from_octets(&[10, 0, 0, 0]));
+    assert_eq!(tree, Sat);
+    assert_eq!(Tree::from_prefixes(vec![]), Unsat);
+  }
+
+  #[test]
+  fn test_add_del_against_naive_set() {
+    use std::collections::HashSet;
+
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+
+    for _ in 0..200 {
+      let mut tree = Tree::new();
+      let mut naive = HashSet::new();
+
+      for _ in 0..20 {
+        let addr = rng.next() as u8;
+        let len = (rng.next() % 9) as usize;
+        let mut prefix = Prefix::from_u8(addr);
+        prefix.truncate(len);
+
+        let covered =
+          (0..=255u8).filter(|a| prefix.matches(&Prefix::from_u8(*a)));
+        if rng.next() & 1 == 0 {
+          naive.extend(covered);
+          tree = tree.add(prefix);
+        } else {
+          covered.for_each(|a| {
+            naive.remove(&a);
+          });
+          tree = tree.del(prefix);
+        }
+      }
+
+      for addr in 0..=255u8 {
+        let expected = naive.contains(&addr);
+        assert_eq!(tree.contains(&Prefix::from_u8(addr)), expected);
+      }
+    }
+  }
+
+  #[test]
+  fn test_difference_asymmetric() {
+    let mut rng = Rng(0xd1b5_4a32_d192_ed03);
+    // the 8-bit addresses in a tree, by brute force
+    let members = |tree: &Tree| {
+      (0..=255u8)
+        .filter(|a| tree.contains(&Prefix::from_u8(*a)))
+        .collect::<Vec<_>>()
+    };
+
+    for _ in 0..200 {
+      // deep, lopsided trees: many long prefixes under a short one
+      let mut base = Prefix::from_u8(rng.next() as u8);
+      base.truncate((rng.next() % 4) as usize);
+      let b = (0..12).fold(Tree::new(), |tree, _| {
+        let mut prefix = base.clone();
+        let tail = Prefix::from_u8(rng.next() as u8);
+        prefix.extend(Prefix(tail.0[base.len()..].to_vec()));
+        prefix.truncate(base.len() + 3 + (rng.next() % 6) as usize);
+        tree.add(prefix)
+      });
+
+      // Sat at the root, and Sat on one side high up with the other
+      // side deep, both before and after optimizing
+      let mut half = Prefix::from_u8(rng.next() as u8);
+      half.truncate(1);
+      let lopsided = b.clone().add(half);
+      let b_members = members(&b);
+      for a in [
+        Sat,
+        Tree::mixed(Sat, Sat),
+        lopsided.clone(),
+        lopsided.optimize(),
+      ] {
+        let expected = members(&a)
+          .into_iter()
+          .filter(|x| !b_members.contains(x))
+          .collect::<Vec<_>>();
+        let diff = a.clone().difference(b.clone());
+        assert_eq!(members(&diff), expected);
+        assert_eq!(members(&a.difference_ref(&b)), expected);
+      }
+    }
+  }
+
+  #[test]
+  fn test_optimize_idempotent() {
+    // arbitrary shapes, including ones add never builds, like
+    // Mixed(Unsat, Unsat) and chains of Mixed(Sat, Sat)
+    fn random_tree(rng: &mut Rng, depth: u32) -> Tree {
+      match rng.next() % 5 {
+        _ if depth == 10 => [Sat, Unsat][(rng.next() & 1) as usize].clone(),
+        0 => Sat,
+        1 => Unsat,
+        _ => {
+          Tree::mixed(random_tree(rng, depth + 1), random_tree(rng, depth + 1))
+        }
+      }
+    }
+
+    // two Sat or two Unsat siblings anywhere
+    fn has_unmerged(tree: &Tree) -> bool {
+      match tree {
+        Mixed(l, r) => {
+          matches!((&**l, &**r), (Sat, Sat) | (Unsat, Unsat))
+            || has_unmerged(l)
+            || has_unmerged(r)
+        }
+        _ => false,
+      }
+    }
+
+    let mut rng = Rng(0x853c_49e6_748f_ea9b);
+    for _ in 0..2000 {
+      let tree = random_tree(&mut rng, 0);
+      let once = tree.clone().optimize();
+      assert_eq!(once.clone().optimize(), once);
+      assert_eq!(once.count_addresses(10), tree.count_addresses(10));
+      assert!(once.coverage_eq(&tree));
+
+      assert!(!has_unmerged(&once));
+      assert_eq!(once.count_prefixes(), once.prefixes().len());
+    }
+  }
+
+  #[test]
+  fn test_ref_combinators() {
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let mut random_tree = || {
+      (0..8).fold(Tree::new(), |tree, _| {
+        let mut prefix = Prefix::from_u8(rng.next() as u8);
+        prefix.truncate((rng.next() % 9) as usize);
+        tree.add(prefix)
+      })
+    };
+
+    for _ in 0..50 {
+      let (a, b) = (random_tree(), random_tree());
+      assert_eq!(a.union_ref(&b), a.clone().union(b.clone()));
+      assert_eq!(a.difference_ref(&b), a.clone().difference(b.clone()));
+      assert_eq!(a.intersection_ref(&b), a.clone().intersection(b.clone()));
+    }
+  }
+
+  #[test]
+  fn test_difference_disjoint() {
+    let mut rng = Rng(0x0123_4567_89ab_cdef);
+    let mut base = Tree::new();
+    for _ in 0..50 {
+      base = base.add(Prefix::from_u8(rng.next() as u8 & !1));
+    }
+
+    // odd addresses never overlap the even ones in base
+    let mut tree = base.clone();
+    for _ in 0..50 {
+      let other = Tree::new().add(Prefix::from_u8(rng.next() as u8 | 1));
+      assert!(!tree.overlaps(&other));
+      assert_eq!(tree.difference_ref(&other), tree.clone().optimize());
+      tree = tree.difference(other);
+    }
+    assert_eq!(tree, base);
+  }
+
+  #[test]
+  fn test_del_many() {
+    let mut rng = Rng(0x1357_9bdf_2468_ace0);
+    let mut prefix = || {
+      let mut p = Prefix::from_u8(rng.next() as u8);
+      p.truncate((rng.next() % 9) as usize);
+      p
+    };
+
+    for _ in 0..20 {
+      let tree = (0..5).fold(Tree::new(), |t, _| t.add(prefix()));
+      let deleted = (0..10).map(|_| prefix()).collect::<Vec<_>>();
+
+      let expected = deleted.iter().cloned().fold(tree.clone(), Tree::del);
+      assert_eq!(tree.del_many(deleted).optimize(), expected.optimize());
+    }
+  }
+
+  #[test]
+  fn test_merge_all() {
+    let mut rng = Rng(0xdead_beef_cafe_f00d);
+    let trees = (0..37)
+      .map(|_| {
+        let mut prefix = Prefix::from_u8(rng.next() as u8);
+        prefix.truncate((rng.next() % 9) as usize);
+        Tree::new().add(prefix)
+      })
+      .collect::<Vec<_>>();
+
+    let naive = trees.iter().cloned().fold(Tree::new(), Tree::union);
+    assert_eq!(Tree::merge_all(trees), naive);
+    assert_eq!(Tree::merge_all(vec![]), Tree::new());
+  }
+
+  fn prefix_from_octets(octets: &[u8]) -> Prefix {
+    let mut prefix = Prefix::empty();
+    for octet in octets {
+      prefix.extend(Prefix::from_u8(*octet));
+    }
+    prefix
+  }
+
+  fn cidr(octets: &[u8], len: usize) -> Prefix {
+    let mut prefix = prefix_from_octets(octets);
+    prefix.truncate(len);
+    prefix
+  }
+
+  #[test]
+  fn test_network_broadcast() {
+    let net = cidr(&[192, 168, 1, 77], 24);
+    assert_eq!(net.network(32), prefix_from_octets(&[192, 168, 1, 0]));
+    assert_eq!(net.broadcast(32), prefix_from_octets(&[192, 168, 1, 255]));
+
+    let host = prefix_from_octets(&[10, 0, 0, 1]);
+    assert_eq!(host.network(32), host);
+    assert_eq!(host.broadcast(32), host);
+  }
+
+  #[test]
+  fn test_bits() {
+    let prefix: Prefix = [1, 0, 1u8][..].into();
+    assert_eq!(prefix.bits().collect::<Vec<_>>(), vec![B1, B0, B1]);
+    assert_eq!(prefix.as_slice(), &[B1, B0, B1]);
+    assert_eq!(Prefix::empty().bits().count(), 0);
+  }
+
+  #[test]
+  fn test_matches() {
+    let net = cidr(&[10, 0, 0, 0], 15);
+
+    assert!(net.matches(&prefix_from_octets(&[10, 0, 0, 1])));
+    // the last bit of the prefix
+    assert!(net.matches(&prefix_from_octets(&[10, 1, 255, 255])));
+    assert!(!net.matches(&prefix_from_octets(&[10, 2, 0, 0])));
+    assert!(Prefix::empty().matches(&net));
+    assert!(!net.matches(&Prefix::empty()));
+  }
+
+  #[test]
+  fn test_from_wildcard_prefix() {
+    let tree =
+      Tree::from_wildcard_prefix(&[Some(10), None, Some(5), None], 24).unwrap();
+    assert_eq!(tree.count_prefixes(), 256);
+    assert!(tree.contains(&prefix_from_octets(&[10, 7, 5, 1])));
+    assert!(!tree.contains(&prefix_from_octets(&[10, 7, 6, 1])));
+
+    // a wildcard covering whole octets collapses into a shorter prefix
+    let tree =
+      Tree::from_wildcard_prefix(&[Some(10), None, None, None], 32).unwrap();
+    let net = cidr(&[10, 0, 0, 0], 8);
+    assert_eq!(tree.prefixes(), vec![net]);
+
+    assert!(
+      Tree::from_wildcard_prefix(&[None, None, None, Some(1)], 32).is_err()
+    );
+    assert!(Tree::from_wildcard_prefix(&[Some(10)], 16).is_err());
+  }
+
+  #[test]
+  fn test_increment_decrement() {
+    let addr = prefix_from_octets(&[10, 0, 0, 255]);
+    let next = prefix_from_octets(&[10, 0, 1, 0]);
+
+    assert_eq!(addr.increment(), Some(next.clone()));
+    assert_eq!(next.decrement(), Some(addr));
+
+    assert_eq!(prefix_from_octets(&[255; 4]).increment(), None);
+
+    assert_eq!(
+      prefix_from_octets(&[10, 0, 0, 255]).checked_add(257),
+      Some(prefix_from_octets(&[10, 0, 2, 0]))
+    );
+    assert!(prefix_from_octets(&[255; 4]).checked_add(0).is_some());
+    assert_eq!(prefix_from_octets(&[255; 4]).checked_add(1), None);
+    assert_eq!(prefix_from_octets(&[0; 4]).checked_add(1 << 32), None);
+    assert_eq!(prefix_from_octets(&[0; 4]).decrement(), None);
+  }
 }
@@ -0,0 +1,72 @@
+use std::fmt;
+
+use failure::Fail;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum NetcalcError {
+  // the input and the reason it's invalid
+  InvalidAddress(String, String),
+  InvalidCidr(String, String),
+  InvalidRange(String, String),
+  InvalidWildcard(String, String),
+  // the length of the line and the limit
+  LineTooLong(usize, usize),
+  UnknownMacro(String),
+  UnrecognizedLine(String),
+  UnrecognizedVersion(String),
+}
+
+use NetcalcError::*;
+
+impl NetcalcError {
+  // a stable identifier of the error kind, unaffected by changes to
+  // the wording of the messages
+  pub fn code(&self) -> &'static str {
+    match self {
+      InvalidAddress(..) => "invalid_address",
+      InvalidCidr(..) => "invalid_cidr",
+      InvalidRange(..) => "invalid_range",
+      InvalidWildcard(..) => "invalid_wildcard",
+      LineTooLong(..) => "line_too_long",
+      UnknownMacro(..) => "unknown_macro",
+      UnrecognizedLine(..) => "unrecognized_line",
+      UnrecognizedVersion(..) => "unrecognized_version",
+    }
+  }
+
+  // the code of any error, "unknown" for errors not from netcalc
+  pub fn code_of(err: &failure::Error) -> &'static str {
+    err
+      .downcast_ref::<NetcalcError>()
+      .map_or("unknown", NetcalcError::code)
+  }
+}
+
+impl fmt::Display for NetcalcError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      InvalidAddress(s, reason) => {
+        write!(f, "Invalid address {}: {}", s, reason)
+      }
+      InvalidCidr(s, reason) => write!(f, "Invalid CIDR {}: {}", s, reason),
+      InvalidRange(s, reason) => write!(f, "Invalid range {}: {}", s, reason),
+      InvalidWildcard(s, reason) => {
+        write!(f, "Invalid wildcard {}: {}", s, reason)
+      }
+      LineTooLong(len, max) => {
+        write!(f, "Line too long: {} bytes, at most {} allowed", len, max)
+      }
+      UnknownMacro(s) => {
+        write!(
+          f,
+          "Unknown macro {0}, define it first with :def {0} = ...",
+          s
+        )
+      }
+      UnrecognizedLine(s) => write!(f, "Unrecognized line: {}", s),
+      UnrecognizedVersion(s) => write!(f, "Unrecognized version: {}", s),
+    }
+  }
+}
+
+impl Fail for NetcalcError {}
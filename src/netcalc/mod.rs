@@ -1,31 +1,163 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::{BufRead, Read};
 use std::net::{Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
 use std::{convert::TryInto, marker::PhantomData};
 
-use failure::{bail, ensure};
+use failure::{bail, ensure, format_err};
 
 mod alg;
+mod error;
 
-pub use alg::{Bit, Prefix, Tree};
+pub use alg::{Bit, Prefix, Prefixes, Tree};
+pub use error::NetcalcError;
 
 type Result<T> = std::result::Result<T, failure::Error>;
 
+// evaluates body with $t as the AddrType of the family, e.g.
+// with_family!(family, |T| App::<T>::render(fmt, tree)), so the
+// dispatch from a family known at runtime to the generic code is
+// written once
+macro_rules! with_family {
+  ($family:expr, |$t:ident| $body:expr) => {{
+    let family: Family = $family;
+    match family {
+      Family::V4 => {
+        type $t = V4;
+        $body
+      }
+      Family::V6 => {
+        type $t = V6;
+        $body
+      }
+    }
+  }};
+}
+
 trait AddrType {
+  const BITS: usize;
+  // length of the longest cidr string
+  const MAX_CIDR_LEN: usize;
+
   fn parse_addr(s: &str) -> Result<Prefix>;
   fn parse_cidr(s: &str) -> Result<Prefix>;
   fn parse_range(s: &str) -> Result<Tree>;
-  fn cidr_from_prefix(p: Prefix) -> Result<String>;
+  fn parse_wildcard(s: &str) -> Result<Tree>;
+  fn addr_from_prefix(p: Prefix) -> Result<String>;
+  // with len_sep between the address and the length, e.g. " " for
+  // "10.0.0.0 8"
+  fn cidr_from_prefix_sep(p: Prefix, len_sep: &str) -> Result<String>;
+  fn cidr_from_prefix(p: Prefix) -> Result<String> {
+    Self::cidr_from_prefix_sep(p, "/")
+  }
+  fn hex_from_prefix(p: Prefix) -> Result<String>;
+  fn padded_from_prefix(p: Prefix, len_sep: &str) -> Result<String>;
+}
+
+// decimal, or hexadecimal with a 0x prefix (e.g. 0x18 for /24)
+fn parse_prefix_len(s: &str) -> Result<u8> {
+  let len = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+    Some(hex) => u8::from_str_radix(hex, 16),
+    None => u8::from_str(s),
+  };
+  len.map_err(|_| format_err!("Invalid prefix length: {}", s))
+}
+
+// a base address and a number of addresses, e.g. 10.0.0.0+256 for
+// 10.0.0.0-10.0.0.255
+fn parse_count<T: AddrType>(s: &str) -> Result<Tree> {
+  let (base, count) = match s.split_once('+') {
+    Some(parts) => parts,
+    None => bail!("Invalid address count"),
+  };
+  let start = T::parse_addr(base)?;
+  let count = u128::from_str(count)
+    .map_err(|_| format_err!("Invalid address count: {}", count))?;
+  ensure!(count >= 1, "Address count must be at least 1");
+  let end = match start.checked_add(count - 1) {
+    Some(end) => end,
+    None => bail!("Address count overflows the address space"),
+  };
+
+  Tree::from_range(&start, &end)
+}
+
+// an address, or a CIDR standing for its first (start of a range) or
+// last (end of a range) address, e.g. "10.0.0.0/24-10.0.3.0/24" for
+// 10.0.0.0-10.0.3.255
+fn parse_range_endpoint<T: AddrType>(s: &str) -> Result<Prefix> {
+  let s = s.trim();
+  if s.contains('/') {
+    T::parse_cidr(s)
+  } else {
+    T::parse_addr(s)
+  }
+}
+
+// a range whose end is the first address after it, e.g.
+// 10.0.0.0..10.0.1.0 for 10.0.0.0-10.0.0.255. A CIDR endpoint stands
+// for its first address on either side.
+fn parse_exclusive_range<T: AddrType>(s: &str) -> Result<Tree> {
+  let (start, end) = match s.split_once("..") {
+    Some(parts) => parts,
+    None => bail!("Invalid exclusive range"),
+  };
+  let start = parse_range_endpoint::<T>(start)?.network(T::BITS);
+  let end = parse_range_endpoint::<T>(end)?.network(T::BITS);
+  Tree::from_range_exclusive(&start, &end)
+}
+
+// like parse_range_endpoint, but also takes the address as a 32-bit
+// integer, as some databases (e.g. GeoIP) store ranges like
+// 3232235521-3232235530
+fn parse_v4_range_endpoint(s: &str) -> Result<Prefix> {
+  parse_range_endpoint::<V4>(s).or_else(|err| {
+    let n = u32::from_str(s.trim()).map_err(|_| err)?;
+    Ok(Prefix::from(Ipv4Addr::from(n)))
+  })
+}
+
+// a CIDR with a list of lengths sharing its base address, e.g.
+// 10.0.0.0/24,25,26 for 10.0.0.0/24, 10.0.0.0/25 and 10.0.0.0/26
+fn parse_length_list<T: AddrType>(s: &str) -> Result<Vec<Prefix>> {
+  let (base, lens) = match s.split_once('/') {
+    Some(parts) => parts,
+    None => bail!("Invalid CIDR"),
+  };
+  lens
+    .split(',')
+    .map(|len| T::parse_cidr(&format!("{}/{}", base, len.trim())))
+    .collect()
+}
+
+// in strict mode, CIDR endpoints of a range must be written as the
+// exact blocks, so e.g. "10.0.0.5/24-10.0.1.5/24" is rejected rather
+// than snapped to 10.0.0.0-10.0.1.255
+fn check_range_alignment<T: AddrType>(s: &str) -> Result<()> {
+  for endpoint in s.split('-').map(str::trim) {
+    if let Some((addr, _)) = endpoint.split_once('/') {
+      let network = T::parse_cidr(endpoint)?.network(T::BITS);
+      ensure!(
+        T::parse_addr(addr)? == network,
+        "{} has host bits set, the block starts at {}",
+        endpoint,
+        T::addr_from_prefix(network)?
+      );
+    }
+  }
+
+  Ok(())
 }
 
 struct V4;
 
 impl AddrType for V4 {
+  const BITS: usize = 32;
+  const MAX_CIDR_LEN: usize = "255.255.255.255/32".len();
+
   fn parse_addr(s: &str) -> Result<Prefix> {
-    let mut prefix = Prefix::empty();
-    let ip_addr = Ipv4Addr::from_str(s)?;
-    for byte in ip_addr.octets() {
-      prefix.extend(Prefix::from_u8(byte));
-    }
+    let prefix = Prefix::from(Ipv4Addr::from_str(s)?);
     ensure!(prefix.len() == 32, "Invalid IPv4 Address");
 
     Ok(prefix)
@@ -36,7 +168,7 @@ impl AddrType for V4 {
       [left, right] => {
         let ip_addr = Ipv4Addr::from_str(left)?;
         let mut addr = Self::parse_addr(ip_addr.to_string().as_str())?;
-        let len = u8::from_str(right)?;
+        let len = parse_prefix_len(right)?;
         ensure!(len <= 32, "Invalid IPv4 CIDR prefix length");
         addr.truncate(len as usize);
         Ok(addr)
@@ -48,37 +180,89 @@ impl AddrType for V4 {
   fn parse_range(s: &str) -> Result<Tree> {
     match s.split('-').collect::<Vec<_>>().as_slice() {
       [left, right] => {
-        let left_ip_addr = Ipv4Addr::from_str(left)?;
-        let right_ip_addr = Ipv4Addr::from_str(right)?;
-        let left = Self::parse_addr(left_ip_addr.to_string().as_str())?;
-        let right = Self::parse_addr(right_ip_addr.to_string().as_str())?;
+        let left = parse_v4_range_endpoint(left)?.network(32);
+        let right = parse_v4_range_endpoint(right)?.broadcast(32);
         Ok(Tree::from_range(&left, &right)?)
       }
       _ => bail!("Invalid IPv4 range"),
     }
   }
 
-  fn cidr_from_prefix(mut prefix: Prefix) -> Result<String> {
-    let len = prefix.len();
-    ensure!(len <= 32, "Invalid prefix length");
+  fn parse_wildcard(s: &str) -> Result<Tree> {
+    let (addr, len) = match s.split_once('/') {
+      Some((addr, len)) => (addr, parse_prefix_len(len)?),
+      None => (s, 32),
+    };
+    ensure!(len <= 32, "Invalid IPv4 CIDR prefix length");
+
+    let octets = addr
+      .split('.')
+      .map(|o| match o {
+        "*" => Ok(None),
+        _ => u8::from_str(o).map(Some),
+      })
+      .collect::<std::result::Result<Vec<_>, _>>()?;
+    ensure!(octets.len() == 4, "Invalid IPv4 wildcard pattern");
+
+    Tree::from_wildcard_prefix(&octets, len as usize)
+  }
+
+  fn addr_from_prefix(prefix: Prefix) -> Result<String> {
+    ensure!(prefix.len() == 32, "Invalid IPv4 address length");
 
-    prefix.right_pad(32, Bit::B0);
     let chunks = prefix.chunks(8)?;
     let [a, b, c, d]: [_; 4] = chunks.as_slice().try_into()?;
     let ip_addr = Ipv4Addr::new(a as u8, b as u8, c as u8, d as u8);
-    Ok(format!("{}/{}", ip_addr, len))
+    Ok(ip_addr.to_string())
+  }
+
+  fn cidr_from_prefix_sep(mut prefix: Prefix, len_sep: &str) -> Result<String> {
+    let len = prefix.len();
+    ensure!(len <= 32, "Invalid prefix length");
+
+    prefix.right_pad(32, Bit::B0);
+    Ok(format!(
+      "{}{}{}",
+      Self::addr_from_prefix(prefix)?,
+      len_sep,
+      len
+    ))
+  }
+
+  // network and netmask as 32-bit hex, e.g. 0x0A000000/0xFF000000
+  fn hex_from_prefix(mut prefix: Prefix) -> Result<String> {
+    let len = prefix.len();
+    ensure!(len <= 32, "Invalid prefix length");
+
+    prefix.right_pad(32, Bit::B0);
+    let mask = u32::MAX.checked_shl(32 - len as u32).unwrap_or(0);
+    Ok(format!("0x{:08X}/0x{:08X}", prefix.to_u64()?, mask))
+  }
+
+  // fixed width, with each octet and the length zero-padded, e.g.
+  // 010.000.000.000/08
+  fn padded_from_prefix(mut prefix: Prefix, len_sep: &str) -> Result<String> {
+    let len = prefix.len();
+    ensure!(len <= 32, "Invalid prefix length");
+
+    prefix.right_pad(32, Bit::B0);
+    let [a, b, c, d] = (prefix.to_u64()? as u32).to_be_bytes();
+    Ok(format!(
+      "{:03}.{:03}.{:03}.{:03}{}{:02}",
+      a, b, c, d, len_sep, len
+    ))
   }
 }
 
 struct V6;
 
 impl AddrType for V6 {
+  const BITS: usize = 128;
+  const MAX_CIDR_LEN: usize =
+    "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff/128".len();
+
   fn parse_addr(s: &str) -> Result<Prefix> {
-    let mut prefix = Prefix::empty();
-    let ip_addr = Ipv6Addr::from_str(s)?;
-    for byte in ip_addr.octets() {
-      prefix.extend(Prefix::from_u8(byte));
-    }
+    let prefix = Prefix::from(Ipv6Addr::from_str(s)?);
     ensure!(prefix.len() == 128, "Invalid IPv6 Address");
 
     Ok(prefix)
@@ -89,7 +273,7 @@ impl AddrType for V6 {
       [left, right] => {
         let ip_addr = Ipv6Addr::from_str(left)?;
         let mut addr = Self::parse_addr(ip_addr.to_string().as_str())?;
-        let len = u8::from_str(right)?;
+        let len = parse_prefix_len(right)?;
         ensure!(len <= 128, "Invalid IPv6 CIDR prefix length");
         addr.truncate(len as usize);
         Ok(addr)
@@ -101,21 +285,21 @@ impl AddrType for V6 {
   fn parse_range(s: &str) -> Result<Tree> {
     match s.split('-').collect::<Vec<_>>().as_slice() {
       [left, right] => {
-        let left_ip_addr = Ipv6Addr::from_str(left)?;
-        let right_ip_addr = Ipv6Addr::from_str(right)?;
-        let left = Self::parse_addr(left_ip_addr.to_string().as_str())?;
-        let right = Self::parse_addr(right_ip_addr.to_string().as_str())?;
+        let left = parse_range_endpoint::<Self>(left)?.network(128);
+        let right = parse_range_endpoint::<Self>(right)?.broadcast(128);
         Ok(Tree::from_range(&left, &right)?)
       }
       _ => bail!("Invalid IPv6 range"),
     }
   }
 
-  fn cidr_from_prefix(mut prefix: Prefix) -> Result<String> {
-    let len = prefix.len();
-    ensure!(len <= 128, "Invalid prefix length");
+  fn parse_wildcard(_s: &str) -> Result<Tree> {
+    bail!("Wildcards are only supported for IPv4")
+  }
+
+  fn addr_from_prefix(prefix: Prefix) -> Result<String> {
+    ensure!(prefix.len() == 128, "Invalid IPv6 address length");
 
-    prefix.right_pad(128, Bit::B0);
     let chunks = prefix.chunks(16)?;
 
     let [a, b, c, d, e, f, g, h]: [_; 8] = chunks.as_slice().try_into()?;
@@ -126,7 +310,28 @@ impl AddrType for V6 {
 
     let ip_addr = Ipv6Addr::new(a, b, c, d, e, f, g, h);
 
-    Ok(format!("{}/{}", ip_addr, len))
+    Ok(ip_addr.to_string())
+  }
+
+  fn cidr_from_prefix_sep(mut prefix: Prefix, len_sep: &str) -> Result<String> {
+    let len = prefix.len();
+    ensure!(len <= 128, "Invalid prefix length");
+
+    prefix.right_pad(128, Bit::B0);
+    Ok(format!(
+      "{}{}{}",
+      Self::addr_from_prefix(prefix)?,
+      len_sep,
+      len
+    ))
+  }
+
+  fn hex_from_prefix(_prefix: Prefix) -> Result<String> {
+    bail!("Hex output is only supported for IPv4")
+  }
+
+  fn padded_from_prefix(_prefix: Prefix, _len_sep: &str) -> Result<String> {
+    bail!("Padded output is only supported for IPv4")
   }
 }
 
@@ -148,16 +353,251 @@ impl<T> Operand<T> {
   where
     T: AddrType,
   {
-    T::parse_addr(s)
-      .map(Operand::Prefix)
-      .or_else(|_| T::parse_cidr(s).map(Operand::Prefix))
-      .or_else(|_| T::parse_range(s).map(Operand::Tree))
+    Self::parse_with(s, false)
+  }
+
+  // strict rejects input that would otherwise be silently corrected
+  fn parse_with(s: &str, strict: bool) -> Result<Self>
+  where
+    T: AddrType,
+  {
+    use NetcalcError::*;
+
+    let s = strip_metadata(s);
+
+    // the whole address space of the family, 0.0.0.0/0 or ::/0
+    if ["default", "any", "all"].contains(&s.to_ascii_lowercase().as_str()) {
+      return Ok(Operand::Prefix(Prefix::empty()));
+    }
+
+    // two whitespace separated addresses, as in spreadsheet columns,
+    // are a range like "10.0.0.1-10.0.0.5"
+    if let [start, end] = s.split_whitespace().collect::<Vec<_>>()[..] {
+      if let (Ok(start), Ok(end)) = (T::parse_addr(start), T::parse_addr(end)) {
+        return Tree::from_range(&start, &end)
+          .map(Operand::Tree)
+          .map_err(|e| InvalidRange(s.to_string(), e.to_string()).into());
+      }
+    }
+
+    // dispatch on the syntax so the error tells which form was meant
+    let (operand, err): (Result<_>, fn(_, _) -> _) = match Syntax::of(s) {
+      Syntax::Complement => {
+        let inner = Self::parse_with(s[1..].trim_start(), strict)?;
+        return Ok(Operand::Tree(inner.to_tree().flip()));
+      }
+      Syntax::Wildcard => {
+        (T::parse_wildcard(s).map(Operand::Tree), InvalidWildcard)
+      }
+      Syntax::ExclusiveRange => (
+        parse_exclusive_range::<T>(s).map(Operand::Tree),
+        InvalidRange,
+      ),
+      Syntax::Count => (parse_count::<T>(s).map(Operand::Tree), InvalidRange),
+      Syntax::Range => {
+        let range = if strict {
+          check_range_alignment::<T>(s).and_then(|_| T::parse_range(s))
+        } else {
+          T::parse_range(s)
+        };
+        (range.map(Operand::Tree), InvalidRange)
+      }
+      Syntax::LengthList => {
+        let tree = parse_length_list::<T>(s)
+          .map(|prefixes| prefixes.into_iter().fold(Tree::new(), Tree::add));
+        (tree.map(Operand::Tree), InvalidCidr)
+      }
+      Syntax::Cidr => (T::parse_cidr(s).map(Operand::Prefix), InvalidCidr),
+      Syntax::Addr => (T::parse_addr(s).map(Operand::Prefix), InvalidAddress),
+    };
+
+    operand.map_err(|e| err(s.to_string(), e.to_string()).into())
+  }
+
+  fn to_tree(&self) -> Tree {
+    match self {
+      Operand::Prefix(p) => Tree::new().add(p.clone()),
+      Operand::Tree(t) => t.clone(),
+      _ => unreachable!(),
+    }
+  }
+
+  // the prefixes of the operand, unoptimized
+  fn push_prefixes(self, prefixes: &mut Vec<Prefix>) {
+    match self {
+      Operand::Prefix(p) => prefixes.push(p),
+      Operand::Tree(t) => prefixes.extend(t.iter_prefixes()),
+      _ => unreachable!(),
+    }
+  }
+
+  // same as tree.add_tree(self.to_tree()), but a prefix is inserted in
+  // place without walking the rest of the tree
+  fn add_to(self, tree: &mut Tree) {
+    match self {
+      Operand::Prefix(p) => tree.insert(&p),
+      Operand::Tree(t) => *tree = std::mem::take(tree).union(t),
+      _ => unreachable!(),
+    }
+  }
+}
+
+// the form of an operand other than a keyword or two columns, told
+// apart by its characters alone
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Syntax {
+  // "!10.0.0.0/8" or "~10.0.0.0/8" for everything but 10.0.0.0/8
+  Complement,
+  // 10.*.0.0
+  Wildcard,
+  // 10.0.0.0..10.0.1.0
+  ExclusiveRange,
+  // 10.0.0.0+256
+  Count,
+  // 10.0.0.0-10.0.0.255
+  Range,
+  // 10.0.0.0/24,25
+  LengthList,
+  // 10.0.0.0/24
+  Cidr,
+  // 10.0.0.1
+  Addr,
+}
+
+impl Syntax {
+  fn of(s: &str) -> Self {
+    if s.starts_with(['!', '~']) {
+      Syntax::Complement
+    } else if s.contains('*') {
+      Syntax::Wildcard
+    } else if s.contains("..") {
+      Syntax::ExclusiveRange
+    } else if s.contains('+') {
+      Syntax::Count
+    } else if s.contains('-') {
+      Syntax::Range
+    } else if s.contains('/') && s.contains(',') {
+      Syntax::LengthList
+    } else if s.contains('/') {
+      Syntax::Cidr
+    } else {
+      Syntax::Addr
+    }
+  }
+}
+
+// the operator for lines without one
+type DefaultOp<T> = fn(Operand<T>) -> TreeOp<T>;
+
+// the state set by directive lines while reading rules
+struct Directives<T> {
+  // the operator of lines without one, set by ":default"
+  default: Option<DefaultOp<T>>,
+  // the sets named by ":def NAME = operands"
+  macros: HashMap<String, Tree>,
+}
+
+impl<T: AddrType> Directives<T> {
+  fn new(default: Option<DefaultOp<T>>) -> Self {
+    Self {
+      default,
+      macros: HashMap::new(),
+    }
+  }
+
+  // "corp = 10.0.0.0/8, 192.168.0.0/16" names the union of the
+  // operands. They may refer to macros defined earlier, including an
+  // earlier definition of the same name, but never to later ones, so
+  // the references can't form a cycle.
+  fn define(&mut self, s: &str, strict: bool) -> Result<()> {
+    let (name, operands) = match s.split_once('=') {
+      Some((name, operands)) => (name.trim(), operands),
+      None => bail!("Invalid macro definition, expected :def NAME = ..."),
+    };
+    ensure!(is_macro_name(name), "Invalid macro name: {}", name);
+
+    // a bare number after a comma is another length of the CIDR before
+    // it, as in 10.0.0.0/24,25
+    let mut items: Vec<String> = vec![];
+    for item in operands.split(',').map(str::trim) {
+      match items.last_mut() {
+        Some(last) if item.bytes().all(|b| b.is_ascii_digit()) => {
+          last.push(',');
+          last.push_str(item);
+        }
+        _ => items.push(item.to_string()),
+      }
+    }
+
+    let mut tree = Tree::new();
+    for item in items {
+      let operand = match self.lookup(&item)? {
+        Some(macro_tree) => macro_tree.clone(),
+        None => Operand::<T>::parse_with(&item, strict)?.to_tree(),
+      };
+      tree = tree.union(operand);
+    }
+    self.macros.insert(name.to_string(), tree);
+    Ok(())
+  }
+
+  // a line whose operand is a macro name, e.g. "+corp" or "add corp",
+  // or "corp" with a default operator
+  fn macro_op(&self, line: &str) -> Result<Option<TreeOp<T>>> {
+    let (op, name) = match TreeOp::split_op(line) {
+      Some(split) => split,
+      None => match self.default {
+        Some(default) => (default, line),
+        None => return Ok(None),
+      },
+    };
+
+    Ok(
+      self
+        .lookup(strip_metadata(name).trim())?
+        .map(|tree| op(Operand::Tree(tree.clone()))),
+    )
+  }
+
+  // the set of a macro, None if s isn't a macro name at all
+  fn lookup(&self, s: &str) -> Result<Option<&Tree>> {
+    if !is_macro_name(s) {
+      return Ok(None);
+    }
+    match self.macros.get(s) {
+      Some(tree) => Ok(Some(tree)),
+      None => Err(NetcalcError::UnknownMacro(s.to_string()).into()),
+    }
+  }
+}
+
+// a letter or "_" followed by letters, digits, "_" and "-", other
+// than the keywords for the whole space and the word operators.
+// Addresses never look like this, as v4 ones start with a digit and
+// v6 ones contain ":".
+fn is_macro_name(s: &str) -> bool {
+  const KEYWORDS: [&str; 6] = ["default", "any", "all", "add", "del", "noop"];
+  let mut chars = s.chars();
+  matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    && !KEYWORDS.contains(&s.to_ascii_lowercase().as_str())
+}
+
+// drops the metadata after a " | ", as in RIR dumps, e.g.
+// "10.0.0.0/8 | AS64500 | corp"
+fn strip_metadata(s: &str) -> &str {
+  match s.split_once(" | ") {
+    Some((operand, _metadata)) => operand.trim_end(),
+    None => s,
   }
 }
 
 enum TreeOp<T> {
   Add(Operand<T>),
   Del(Operand<T>),
+  Intersect(Operand<T>),
+  // replaces the whole set
+  Set(Operand<T>),
   Noop,
 }
 
@@ -166,18 +606,80 @@ impl<T> TreeOp<T> {
   where
     T: AddrType,
   {
-    // otherwise the [..1] will panic
-    if s.is_empty() {
-      return Ok(TreeOp::Noop);
+    Self::parse_with(s, false)
+  }
+
+  fn parse_with(s: &str, strict: bool) -> Result<Self>
+  where
+    T: AddrType,
+  {
+    // the operator is only recognized at the start of the line, so a
+    // "#" is never confused with a part of an address
+    let s = s.trim();
+    if let Some((op, operand)) = Self::split_op(s) {
+      return Ok(op(Operand::parse_with(operand, strict)?));
     }
 
-    match &s[..1] {
-      "+" => Ok(TreeOp::Add(Operand::parse(&s[1..])?)),
-      "-" => Ok(TreeOp::Del(Operand::parse(&s[1..])?)),
-      "#" => Ok(TreeOp::Noop),
+    let word = s.split(char::is_whitespace).next().unwrap_or_default();
+    match s.chars().next() {
+      _ if word.eq_ignore_ascii_case("noop") => Ok(TreeOp::Noop),
+      // strict mode has no comments, so a stray line can't be hidden
+      // by commenting it out by accident
+      Some('#') if !strict => Ok(TreeOp::Noop),
       // empty line
-      "" => Ok(TreeOp::Noop),
-      _ => bail!("Unrecognized line: {}", s),
+      None => Ok(TreeOp::Noop),
+      Some(_) => Err(NetcalcError::UnrecognizedLine(s.to_string()).into()),
+    }
+  }
+
+  // the operator a trimmed line starts with and the operand after it,
+  // e.g. "+10.0.0.0/8" or the word operator in "add 10.0.0.0/8". None
+  // if the line doesn't start with an operator taking an operand.
+  fn split_op(s: &str) -> Option<(DefaultOp<T>, &str)> {
+    let (word, rest) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+    let (op, operand): (DefaultOp<T>, _) =
+      match word.to_ascii_lowercase().as_str() {
+        "add" => (TreeOp::Add, rest),
+        "del" => (TreeOp::Del, rest),
+        _ => {
+          let mut chars = s.chars();
+          let op: DefaultOp<T> = match chars.next()? {
+            '+' => TreeOp::Add,
+            '-' => TreeOp::Del,
+            '&' => TreeOp::Intersect,
+            '=' => TreeOp::Set,
+            _ => return None,
+          };
+          (op, chars.as_str())
+        }
+      };
+    Some((op, operand.trim_start()))
+  }
+
+  // like parse, but a line without an operator gets the default one,
+  // e.g. TreeOp::Add to add bare CIDRs
+  fn parse_default(s: &str, default: DefaultOp<T>, strict: bool) -> Result<Self>
+  where
+    T: AddrType,
+  {
+    match Self::parse_with(s, strict) {
+      Err(err) if NetcalcError::code_of(&err) == "unrecognized_line" => {
+        Ok(default(Operand::parse_with(s, strict)?))
+      }
+      op => op,
+    }
+  }
+
+  // the argument of a ":default" directive, "none" to require explicit
+  // operators again
+  fn parse_default_directive(s: &str) -> Result<Option<DefaultOp<T>>> {
+    match s {
+      "+" => Ok(Some(TreeOp::Add)),
+      "-" => Ok(Some(TreeOp::Del)),
+      "none" => Ok(None),
+      _ => {
+        Err(NetcalcError::UnrecognizedLine(format!(":default {}", s)).into())
+      }
     }
   }
 
@@ -187,6 +689,8 @@ impl<T> TreeOp<T> {
       TreeOp::Del(Operand::Prefix(p)) => tree.del(p),
       TreeOp::Add(Operand::Tree(o)) => tree.add_tree(o),
       TreeOp::Del(Operand::Tree(o)) => tree.del_tree(o),
+      TreeOp::Intersect(o) => tree.intersection(o.to_tree()),
+      TreeOp::Set(o) => o.to_tree(),
       TreeOp::Noop => tree,
       _ => unreachable!(),
     }
@@ -194,31 +698,2781 @@ impl<T> TreeOp<T> {
 }
 
 impl<T: AddrType> App<T> {
-  fn convert(sep: &str, s: &str) -> Result<String> {
+  fn convert_reader<R: BufRead>(
+    fmt: &Format,
+    opts: &ParseOptions,
+    reader: R,
+  ) -> Result<String> {
+    let tree = Self::read_tree(reader, None, opts)?;
+    Ok(fmt.finish(Self::render(fmt, tree)?))
+  }
+
+  fn aggregate<R: BufRead>(
+    fmt: &Format,
+    opts: &ParseOptions,
+    reader: R,
+  ) -> Result<String> {
+    let tree = Self::read_tree(reader, Some(TreeOp::Add), opts)?;
+    Ok(fmt.finish(Self::render(fmt, tree)?))
+  }
+
+  fn free<R: BufRead>(
+    fmt: &Format,
+    opts: &ParseOptions,
+    universe: &str,
+    reader: R,
+  ) -> Result<String> {
+    let universe = Operand::<T>::parse(universe)?.to_tree();
+    let used = Self::read_tree(reader, Some(TreeOp::Add), opts)?;
+    let free = universe.difference(used);
+    Ok(fmt.finish(Self::render(fmt, free)?))
+  }
+
+  // annotates each output prefix with the input lines that still
+  // contribute addresses to it, leaving out deletions and additions
+  // fully overridden by later lines
+  fn explain<R: BufRead>(
+    fmt: &Format,
+    opts: &ParseOptions,
+    reader: R,
+  ) -> Result<String> {
+    let mut ops = vec![];
+    let mut directives = Directives::new(None);
+    for line in opts.lines(reader) {
+      let line = line?;
+      let op = Self::parse_line(&line, &mut directives, opts)?;
+      ops.push((line.trim().to_string(), op));
+    }
+
+    // going backwards, the addresses an added operand contributes are
+    // the ones no later line has decided on yet
+    let mut decided = Tree::new();
+    let mut sources = vec![];
+    for (line, op) in ops.into_iter().rev() {
+      let added = match op {
+        TreeOp::Add(operand) => {
+          let added = operand.to_tree().difference_ref(&decided);
+          operand.add_to(&mut decided);
+          added
+        }
+        TreeOp::Set(operand) => {
+          let added = operand.to_tree().difference(decided);
+          decided = Tree::new().flip();
+          added
+        }
+        TreeOp::Del(operand) => {
+          operand.add_to(&mut decided);
+          continue;
+        }
+        TreeOp::Intersect(operand) => {
+          decided = decided.add_tree(operand.to_tree().flip());
+          continue;
+        }
+        TreeOp::Noop => continue,
+      };
+      if !added.is_empty() {
+        sources.push((line, added));
+      }
+    }
+    sources.reverse();
+
+    // the contributions are disjoint, and together they're the result
     let mut tree = Tree::new();
+    for (_, added) in &sources {
+      for prefix in added.iter_prefixes() {
+        tree.insert(&prefix);
+      }
+    }
 
-    for line in s.lines() {
-      let line = line.trim();
-      let op: TreeOp<T> = TreeOp::parse(line)?;
+    // each contributed prefix is within exactly one output prefix
+    let outputs = tree.optimize().iter_prefixes().collect::<Vec<_>>();
+    let mut lines = vec![vec![]; outputs.len()];
+    for (line, added) in &sources {
+      for prefix in added.iter_prefixes() {
+        let i = outputs
+          .binary_search_by(|output| {
+            output.partial_cmp(&prefix).unwrap_or(Ordering::Equal)
+          })
+          .map_err(|_| format_err!("No output prefix contains {:?}", prefix))?;
+        if lines[i].last() != Some(&line.as_str()) {
+          lines[i].push(line.as_str());
+        }
+      }
+    }
+
+    let mut out = vec![];
+    for (prefix, lines) in outputs.into_iter().zip(lines) {
+      out.push(format!(
+        "{} <= {{{}}}",
+        T::cidr_from_prefix(prefix)?,
+        lines.join(", ")
+      ));
+    }
+
+    Ok(fmt.finish(out.join(&fmt.sep)))
+  }
+
+  // the added operands in the order they were entered, each rendered
+  // on its own rather than aggregated with the others. Only what's
+  // left in the final set is output, so an operand partially deleted
+  // later shows up as its remaining prefixes, and one fully deleted or
+  // already output doesn't show up at all.
+  fn as_entered<R: BufRead>(
+    fmt: &Format,
+    opts: &ParseOptions,
+    reader: R,
+  ) -> Result<String> {
+    let mut tree = Tree::new();
+    let mut added = vec![];
+    let mut directives = Directives::new(None);
+
+    for line in opts.lines(reader) {
+      let op = Self::parse_line(&line?, &mut directives, opts)?;
+      match &op {
+        TreeOp::Add(operand) => added.push(operand.to_tree()),
+        TreeOp::Set(operand) => added = vec![operand.to_tree()],
+        _ => (),
+      }
       tree = op.apply(tree);
     }
 
-    let cidrs = tree
-      .prefixes()
-      .into_iter()
-      .map(T::cidr_from_prefix)
-      .collect::<Result<Vec<_>>>()?
-      .join(sep);
+    let mut out = vec![];
+    for operand in added {
+      let (remaining, emitted) = tree.difference_with_report(operand);
+      tree = remaining;
+      for prefix in emitted.optimize().iter_prefixes() {
+        out.push(Self::format_prefix(fmt, prefix)?);
+      }
+    }
 
-    Ok(cidrs)
+    Ok(fmt.finish(out.join(&fmt.sep)))
   }
-}
 
-#[allow(unused)]
-pub fn convert(version: &str, sep: &str, s: &str) -> Result<String> {
-  match version {
-    "v4" => App::<V4>::convert(sep, s),
-    "v6" => App::<V6>::convert(sep, s),
-    _ => bail!("Unrecognized version: {}", version),
+  // the output prefixes that weren't themselves input, i.e. the
+  // supernets made by merging, each with the inputs merged into it,
+  // e.g. "10.0.0.0/23 <= 10.0.0.0/24, 10.0.1.0/24"
+  fn supernets<R: BufRead>(
+    fmt: &Format,
+    opts: &ParseOptions,
+    reader: R,
+  ) -> Result<String> {
+    let mut tree = Tree::new();
+    let mut inputs = vec![];
+    let mut directives = Directives::new(None);
+
+    for line in opts.lines(reader) {
+      let op = Self::parse_line(&line?, &mut directives, opts)?;
+      if let TreeOp::Add(operand) | TreeOp::Set(operand) = &op {
+        inputs.extend(operand.to_tree().prefixes());
+      }
+      tree = op.apply(tree);
+    }
+
+    // the output prefix within which each input lies, found with a
+    // binary search as the outputs are disjoint and in order. What's
+    // left of an input after deletions isn't within a single one.
+    let outputs = tree.optimize().iter_prefixes().collect::<Vec<_>>();
+    let mut merged = vec![vec![]; outputs.len()];
+    let mut was_input = vec![false; outputs.len()];
+    for input in inputs {
+      let Ok(i) = outputs.binary_search_by(|output| {
+        output.partial_cmp(&input).unwrap_or(Ordering::Equal)
+      }) else {
+        continue;
+      };
+      if outputs[i] == input {
+        was_input[i] = true;
+      } else if outputs[i].len() < input.len() {
+        merged[i].push(T::cidr_from_prefix(input)?);
+      }
+    }
+
+    let mut out = vec![];
+    for ((prefix, merged), was_input) in
+      outputs.into_iter().zip(merged).zip(was_input)
+    {
+      if was_input || merged.is_empty() {
+        continue;
+      }
+      out.push(format!(
+        "{} <= {}",
+        T::cidr_from_prefix(prefix)?,
+        merged.join(", ")
+      ));
+    }
+
+    Ok(fmt.finish(out.join(&fmt.sep)))
+  }
+
+  // a warning for each line whose operand, e.g. a range not aligned
+  // to CIDR boundaries, expands to more than max_prefixes prefixes.
+  // Macros are skipped, as a set of many CIDRs is what they're for.
+  fn check_ranges<R: BufRead>(
+    opts: &ParseOptions,
+    reader: R,
+    max_prefixes: usize,
+  ) -> Result<Vec<String>> {
+    let mut warnings = vec![];
+    let mut directives = Directives::new(None);
+
+    for (i, line) in opts.lines(reader).enumerate() {
+      let line = line?;
+      let line = line.trim();
+      if directives.macro_op(line)?.is_some() {
+        continue;
+      }
+      if let TreeOp::Add(Operand::Tree(t))
+      | TreeOp::Del(Operand::Tree(t))
+      | TreeOp::Intersect(Operand::Tree(t))
+      | TreeOp::Set(Operand::Tree(t)) =
+        Self::parse_line(line, &mut directives, opts)?
+      {
+        let count = t.count_prefixes();
+        if count > max_prefixes {
+          warnings.push(format!(
+            "line {}: {} expands to {} prefixes, more than {}",
+            i + 1,
+            line,
+            count,
+            max_prefixes
+          ));
+        }
+      }
+    }
+
+    Ok(warnings)
+  }
+
+  // a ":default +" line makes later lines without an operator adds
+  fn read_tree<R: BufRead>(
+    reader: R,
+    default: Option<DefaultOp<T>>,
+    opts: &ParseOptions,
+  ) -> Result<Tree> {
+    Ok(Self::read_tree_counted(reader, default, opts)?.0)
+  }
+
+  // also returns the number of operations applied, i.e. lines other
+  // than blanks, comments and directives
+  fn read_tree_counted<R: BufRead>(
+    reader: R,
+    default: Option<DefaultOp<T>>,
+    opts: &ParseOptions,
+  ) -> Result<(Tree, usize)> {
+    let mut tree = Tree::new();
+    let mut applied = 0;
+    let mut directives = Directives::new(default);
+    // as long as the rules only add, the prefixes are collected and
+    // built into a tree at once rather than merged in line by line
+    let mut added = Some(vec![]);
+
+    for line in opts.lines(reader) {
+      let op = Self::parse_line(&line?, &mut directives, opts)?;
+      match (&mut added, op) {
+        (_, TreeOp::Noop) => continue,
+        (Some(added), TreeOp::Add(operand)) => operand.push_prefixes(added),
+        (added, op) => {
+          if let Some(added) = added.take() {
+            tree = Tree::from_prefixes(added);
+          }
+          tree = op.apply(tree);
+        }
+      }
+      applied += 1;
+    }
+
+    if let Some(added) = added {
+      tree = Tree::from_prefixes(added);
+    }
+    Ok((tree, applied))
+  }
+
+  // like read_tree, but a line that fails to parse is skipped rather
+  // than aborting, and reported along with its line number (from 1)
+  fn read_tree_lossy<R: BufRead>(
+    reader: R,
+    opts: &ParseOptions,
+  ) -> Result<(Tree, Vec<(usize, failure::Error)>)> {
+    let mut tree = Tree::new();
+    let mut directives = Directives::new(None);
+    let mut errors = vec![];
+
+    for (i, line) in opts.lines(reader).enumerate() {
+      let op = match line {
+        Ok(line) => Self::parse_line(&line, &mut directives, opts),
+        // a line too long to read is skipped like any other bad line
+        Err(err) if NetcalcError::code_of(&err) == "line_too_long" => Err(err),
+        Err(err) => return Err(err),
+      };
+      match op {
+        Ok(op) => tree = op.apply(tree),
+        Err(err) => errors.push((i + 1, err)),
+      }
+    }
+
+    Ok((tree, errors))
+  }
+
+  // ":default" and ":def" directives update the directives and are
+  // no-ops themselves
+  fn parse_line(
+    line: &str,
+    directives: &mut Directives<T>,
+    opts: &ParseOptions,
+  ) -> Result<TreeOp<T>> {
+    let line = line.trim();
+
+    if let Some(arg) = line.strip_prefix(":default") {
+      directives.default = TreeOp::parse_default_directive(arg.trim())?;
+      return Ok(TreeOp::Noop);
+    }
+    if let Some(arg) = line.strip_prefix(":def ") {
+      directives.define(arg, opts.strict)?;
+      return Ok(TreeOp::Noop);
+    }
+    if let Some(op) = directives.macro_op(line)? {
+      return Ok(op);
+    }
+
+    match directives.default {
+      Some(default) => TreeOp::parse_default(line, default, opts.strict),
+      None => TreeOp::parse_with(line, opts.strict),
+    }
+  }
+
+  // renders the prefixes joined by the separator, without the
+  // trailing separator
+  fn render(fmt: &Format, tree: Tree) -> Result<String> {
+    let tree = tree.optimize();
+    if let Some(group_len) = fmt.group_len {
+      return Self::render_grouped(fmt, &tree, group_len);
+    }
+
+    if let Some(quote) = fmt.array_quote {
+      let items = Self::ordered_prefixes(fmt, &tree)
+        .map(|p| Ok(format!("{0}{1}{0}", quote, Self::format_prefix(fmt, p)?)))
+        .collect::<Result<Vec<_>>>()?;
+      return Ok(format!("[{}]", items.join(", ")));
+    }
+
+    let count = tree.count_prefixes();
+    let mut out =
+      String::with_capacity(count * (T::MAX_CIDR_LEN + fmt.sep.len()));
+
+    for (i, prefix) in Self::ordered_prefixes(fmt, &tree).enumerate() {
+      if i > 0 {
+        out.push_str(&fmt.sep);
+      }
+      out.push_str(&Self::format_prefix(fmt, prefix)?);
+    }
+
+    Ok(out)
+  }
+
+  // only the first limit prefixes of render, along with the number of
+  // prefixes left out. The rest are counted, not formatted.
+  fn render_preview(
+    fmt: &Format,
+    tree: Tree,
+    limit: usize,
+  ) -> Result<(String, usize)> {
+    let tree = tree.optimize();
+    let shown = Self::ordered_prefixes(fmt, &tree)
+      .take(limit)
+      .map(|prefix| Self::format_prefix(fmt, prefix))
+      .collect::<Result<Vec<_>>>()?;
+    let left_out = tree.count_prefixes() - shown.len();
+    Ok((shown.join(&fmt.sep), left_out))
+  }
+
+  // the prefixes of the optimized tree in the order they're output
+  fn ordered_prefixes<'a>(
+    fmt: &Format,
+    tree: &'a Tree,
+  ) -> Box<dyn Iterator<Item = Prefix> + 'a> {
+    if fmt.reverse {
+      Box::new(tree.prefixes().into_iter().rev())
+    } else {
+      Box::new(tree.iter_prefixes())
+    }
+  }
+
+  fn format_prefix(fmt: &Format, prefix: Prefix) -> Result<String> {
+    let len_sep = fmt.len_sep.as_deref().unwrap_or("/");
+    match fmt.notation {
+      Notation::Cidr => T::cidr_from_prefix_sep(prefix, len_sep),
+      Notation::Hex => T::hex_from_prefix(prefix),
+      Notation::Padded => T::padded_from_prefix(prefix, len_sep),
+    }
+  }
+
+  // one prefix per line, where prefixes longer than group_len are
+  // indented under their supernet of that length
+  fn render_grouped(
+    fmt: &Format,
+    tree: &Tree,
+    group_len: usize,
+  ) -> Result<String> {
+    let mut lines = vec![];
+    let mut group = None;
+
+    for prefix in Self::ordered_prefixes(fmt, tree) {
+      if prefix.len() <= group_len {
+        group = None;
+        lines.push(Self::format_prefix(fmt, prefix)?);
+        continue;
+      }
+
+      let mut supernet = prefix.clone();
+      supernet.truncate(group_len);
+      if group.as_ref() != Some(&supernet) {
+        lines.push(Self::format_prefix(fmt, supernet.clone())?);
+        group = Some(supernet);
+      }
+      lines.push(format!("  {}", Self::format_prefix(fmt, prefix)?));
+    }
+
+    Ok(lines.join(fmt.output_sep()))
+  }
+
+  // a finding for each line whose operand is a single CIDR or a list
+  // of lengths of one, with or without an operator. Each line goes
+  // through the same parser as convert, so an invalid line fails the
+  // check, while comments, directives and operands of any other form,
+  // e.g. ranges and macros, are skipped.
+  fn check_host_bits<R: BufRead>(
+    opts: &ParseOptions,
+    reader: R,
+  ) -> Result<Vec<HostBitsFinding>> {
+    let mut findings = vec![];
+    let mut directives = Directives::new(Some(TreeOp::Add));
+
+    for (i, line) in opts.lines(reader).enumerate() {
+      let line = line?;
+      if let TreeOp::Noop = Self::parse_line(&line, &mut directives, opts)? {
+        continue;
+      }
+      let line = strip_metadata(line.trim());
+      let cidr = TreeOp::<T>::split_op(line).map_or(line, |(_, cidr)| cidr);
+      if !matches!(Syntax::of(cidr), Syntax::Cidr | Syntax::LengthList) {
+        continue;
+      }
+
+      // one finding for each length of a list, e.g. 10.0.0.0/24,25
+      let prefixes = parse_length_list::<T>(cidr)
+        .map_err(|e| NetcalcError::InvalidCidr(cidr.into(), e.to_string()))?;
+      let (addr, _) = cidr.split_once('/').unwrap();
+      let addr = T::parse_addr(addr)?;
+      for prefix in prefixes {
+        findings.push(HostBitsFinding {
+          line: i + 1,
+          host_bits_set: addr != prefix.network(T::BITS),
+          normalized: T::cidr_from_prefix(prefix)?,
+        });
+      }
+    }
+
+    Ok(findings)
+  }
+
+  fn density_report(tree: Tree, bucket_len: u8) -> Result<Vec<(String, u128)>> {
+    tree
+      .density_report(bucket_len, T::BITS as u32)
+      .into_iter()
+      .map(|(bucket, count)| Ok((T::cidr_from_prefix(bucket)?, count)))
+      .collect()
+  }
+
+  // each section is read on its own, from an empty set and without
+  // the directives of the ones before it
+  fn convert_sections<R: BufRead>(
+    fmt: &Format,
+    opts: &ParseOptions,
+    reader: R,
+  ) -> Result<String> {
+    let mut sections = vec![];
+    // the implicit first section, before any "---"
+    let mut label = None;
+    let mut rules = String::new();
+
+    for line in opts.lines(reader) {
+      let line = line?;
+      let Some(next) = line.trim().strip_prefix("---") else {
+        rules.push_str(&line);
+        rules.push('\n');
+        continue;
+      };
+      // nothing before the first separator isn't a section
+      if label.is_some() || !rules.trim().is_empty() {
+        sections.push((label, rules));
+      }
+      label = Some(next.trim().to_string()).filter(|l| !l.is_empty());
+      rules = String::new();
+    }
+    sections.push((label, rules));
+
+    let mut out = vec![];
+    for (i, (label, rules)) in sections.into_iter().enumerate() {
+      let tree = Self::read_tree(rules.as_bytes(), None, opts)?;
+      let label = label.unwrap_or_else(|| format!("section {}", i + 1));
+      out.push(format!("--- {}", label));
+      // an empty set is only its header
+      let body = Self::render(fmt, tree)?;
+      if !body.is_empty() {
+        out.push(body);
+      }
+    }
+
+    Ok(fmt.finish(out.join("\n")))
+  }
+
+  fn exclude_range(
+    fmt: &Format,
+    range: &str,
+    universe: Option<&str>,
+  ) -> Result<String> {
+    let range = parse_range_operand::<T>(range)?;
+    let universe = match universe {
+      Some(universe) => Operand::<T>::parse(universe)?.to_tree(),
+      None => Tree::new().flip(),
+    };
+    Ok(fmt.finish(Self::render(fmt, universe.difference(range))?))
+  }
+
+  fn fit_range(range: &str, max_prefixes: usize) -> Result<RangeFit> {
+    ensure!(
+      max_prefixes > 0,
+      "The budget must allow at least one prefix"
+    );
+    let exact = parse_range_operand::<T>(range)?;
+    let bits = T::BITS as u32;
+    let cover = exact
+      .cover_with_budget(max_prefixes, bits)
+      .ok_or_else(|| format_err!("The range can't be covered"))?;
+
+    Ok(RangeFit {
+      exact_prefixes: exact.count_prefixes(),
+      prefixes: cover
+        .iter_prefixes()
+        .map(T::cidr_from_prefix)
+        .collect::<Result<_>>()?,
+      // the difference rather than the counts subtracted, as the
+      // count of the whole v6 space saturates
+      extra_addresses: cover.difference_ref(&exact).count_addresses(bits),
+    })
+  }
+
+  fn cidr_range(s: &str) -> Result<(String, String)> {
+    let prefix = T::parse_cidr(s)?;
+    Ok((
+      T::addr_from_prefix(prefix.network(T::BITS))?,
+      T::addr_from_prefix(prefix.broadcast(T::BITS))?,
+    ))
+  }
+}
+
+// controls how the resulting set is rendered
+#[derive(Clone, Debug, Default)]
+pub struct Format {
+  // inserted between prefixes
+  pub sep: String,
+  // also end a non-empty output with the separator. An empty set is
+  // always rendered as an empty string.
+  pub trailing_sep: bool,
+  // list prefixes one per line, grouped under their supernets of this
+  // length, in place of sep. The set itself is unchanged, this is only
+  // for display.
+  pub group_len: Option<usize>,
+  // how each prefix is written
+  pub notation: Notation,
+  // a list literal instead of separated prefixes, with each prefix
+  // in the quote, e.g. '\'' for Python or '"' for JavaScript
+  pub array_quote: Option<char>,
+  // output the prefixes in descending rather than ascending order
+  pub reverse: bool,
+  // between the address and the length of a CIDR, e.g. " " for
+  // "10.0.0.0 8". None for the usual "/".
+  pub len_sep: Option<String>,
+}
+
+// controls how the rules are read, separately from how the result is
+// rendered
+#[derive(Clone, Debug)]
+pub struct ParseOptions {
+  // reject sloppy input instead of correcting or ignoring it, e.g. a
+  // range between CIDRs that aren't the exact boundaries of their
+  // blocks, or a "#" comment line
+  pub strict: bool,
+  // reject input lines longer than this many bytes, so that e.g. a
+  // huge paste without newlines doesn't blow up parsing. Such a line
+  // is never read into memory as a whole. The default is generous,
+  // None lifts the limit.
+  pub max_line_len: Option<usize>,
+}
+
+// the default limit on the length of input lines, far longer than
+// any rule needs
+pub const DEFAULT_MAX_LINE_LEN: usize = 1024;
+
+impl Default for ParseOptions {
+  fn default() -> Self {
+    Self {
+      strict: false,
+      max_line_len: Some(DEFAULT_MAX_LINE_LEN),
+    }
+  }
+}
+
+impl ParseOptions {
+  fn lines<R: BufRead>(&self, reader: R) -> Lines<R> {
+    Lines {
+      reader,
+      max_len: self.max_line_len,
+      buf: vec![],
+    }
+  }
+}
+
+// like BufRead::lines, but a line longer than max_len gives a
+// LineTooLong error in its place, and is skipped without reading more
+// than max_len bytes of it into memory
+struct Lines<R> {
+  reader: R,
+  max_len: Option<usize>,
+  buf: Vec<u8>,
+}
+
+impl<R: BufRead> Iterator for Lines<R> {
+  type Item = Result<String>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.buf.clear();
+    // room for a "\r\n" after a line of max_len bytes
+    let limit = self.max_len.map_or(u64::MAX, |max| max as u64 + 2);
+    let read = match (&mut self.reader)
+      .take(limit)
+      .read_until(b'\n', &mut self.buf)
+    {
+      Ok(0) => return None,
+      Ok(read) => read,
+      Err(err) => return Some(Err(err.into())),
+    };
+
+    let cut_off = read as u64 == limit && self.buf.last() != Some(&b'\n');
+    if self.buf.last() == Some(&b'\n') {
+      self.buf.pop();
+      if self.buf.last() == Some(&b'\r') {
+        self.buf.pop();
+      }
+    }
+
+    match self.max_len {
+      Some(max) if self.buf.len() > max => {
+        let rest = if cut_off {
+          match skip_line(&mut self.reader) {
+            Ok(rest) => rest,
+            Err(err) => return Some(Err(err.into())),
+          }
+        } else {
+          0
+        };
+        let len = self.buf.len() + rest;
+        Some(Err(NetcalcError::LineTooLong(len, max).into()))
+      }
+      _ => Some(
+        String::from_utf8(std::mem::take(&mut self.buf)).map_err(Into::into),
+      ),
+    }
+  }
+}
+
+// consumes the rest of the current line, including the newline,
+// returning its length without it
+fn skip_line<R: BufRead>(reader: &mut R) -> std::io::Result<usize> {
+  let mut skipped = 0;
+  loop {
+    let buf = reader.fill_buf()?;
+    if buf.is_empty() {
+      return Ok(skipped);
+    }
+    match buf.iter().position(|b| *b == b'\n') {
+      Some(i) => {
+        reader.consume(i + 1);
+        return Ok(skipped + i);
+      }
+      None => {
+        let len = buf.len();
+        reader.consume(len);
+        skipped += len;
+      }
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Notation {
+  // 10.0.0.0/8
+  #[default]
+  Cidr,
+  // network and netmask in hex, 0x0A000000/0xFF000000 (IPv4 only)
+  Hex,
+  // zero-padded to a fixed width so that a list lines up in columns,
+  // 010.000.000.000/08 (IPv4 only)
+  Padded,
+}
+
+impl Format {
+  // The escape sequences \n, \t, \r and \\ in sep are interpreted, so
+  // e.g. a literal "\t" gives tab-separated output. Other backslashes
+  // are kept as is.
+  pub fn new(sep: &str) -> Self {
+    Self {
+      sep: unescape(sep),
+      ..Self::default()
+    }
+  }
+
+  fn finish(&self, mut out: String) -> String {
+    if self.trailing_sep && !out.is_empty() {
+      out.push_str(self.output_sep());
+    }
+    out
+  }
+
+  // the separator actually used between prefixes, a newline when
+  // grouped as the groups are shown by indenting the lines
+  fn output_sep(&self) -> &str {
+    match self.group_len {
+      Some(_) => "\n",
+      None => &self.sep,
+    }
+  }
+}
+
+fn unescape(s: &str) -> String {
+  let mut out = String::with_capacity(s.len());
+  let mut chars = s.chars();
+
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      out.push(c);
+      continue;
+    }
+    match chars.next() {
+      Some('n') => out.push('\n'),
+      Some('t') => out.push('\t'),
+      Some('r') => out.push('\r'),
+      Some('\\') => out.push('\\'),
+      Some(other) => {
+        out.push('\\');
+        out.push(other);
+      }
+      None => out.push('\\'),
+    }
+  }
+
+  out
+}
+
+pub fn convert(version: &str, sep: &str, s: &str) -> Result<String> {
+  convert_with(version, &Format::new(sep), &ParseOptions::default(), s)
+}
+
+pub fn convert_with(
+  version: &str,
+  fmt: &Format,
+  opts: &ParseOptions,
+  s: &str,
+) -> Result<String> {
+  convert_reader_with(version, fmt, opts, s.as_bytes())
+}
+
+// same as convert, but reads the input line by line
+pub fn convert_reader<R: BufRead>(
+  version: &str,
+  sep: &str,
+  reader: R,
+) -> Result<String> {
+  let opts = ParseOptions::default();
+  convert_reader_with(version, &Format::new(sep), &opts, reader)
+}
+
+pub fn convert_reader_with<R: BufRead>(
+  version: &str,
+  fmt: &Format,
+  opts: &ParseOptions,
+  reader: R,
+) -> Result<String> {
+  match version {
+    "mixed" => convert_mixed(fmt, opts, reader),
+    _ => with_family!(version.parse()?, |T| {
+      App::<T>::convert_reader(fmt, opts, reader)
+    }),
+  }
+}
+
+// like convert_with, along with the number of operations applied. An
+// empty output with no operations means there were no rules at all,
+// rather than rules cancelling each other out.
+pub fn convert_counted(
+  version: &str,
+  fmt: &Format,
+  opts: &ParseOptions,
+  s: &str,
+) -> Result<(String, usize)> {
+  if version == "mixed" {
+    let (v4, v6, applied) = read_mixed(opts, s.as_bytes())?;
+    return Ok((render_mixed(fmt, v4, v6)?, applied));
+  }
+  with_family!(version.parse()?, |T| {
+    let (tree, applied) =
+      App::<T>::read_tree_counted(s.as_bytes(), None, opts)?;
+    Ok((fmt.finish(App::<T>::render(fmt, tree)?), applied))
+  })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Family {
+  V4,
+  V6,
+}
+
+impl Family {
+  // the family of an address, CIDR or range. Only IPv6 addresses
+  // contain colons.
+  pub fn detect(s: &str) -> Self {
+    if s.contains(':') {
+      Family::V6
+    } else {
+      Family::V4
+    }
+  }
+
+  // the number of bits in an address
+  pub fn width(self) -> usize {
+    with_family!(self, |T| T::BITS)
+  }
+}
+
+// a version argument, "v4" or "v6"
+impl FromStr for Family {
+  type Err = failure::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "v4" => Ok(Family::V4),
+      "v6" => Ok(Family::V6),
+      _ => Err(NetcalcError::UnrecognizedVersion(s.to_string()).into()),
+    }
+  }
+}
+
+// a single prefix along with its family, for callers handling both
+// families without a type parameter
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IpPrefix {
+  V4(Prefix),
+  V6(Prefix),
+}
+
+impl IpPrefix {
+  pub fn family(&self) -> Family {
+    match self {
+      IpPrefix::V4(_) => Family::V4,
+      IpPrefix::V6(_) => Family::V6,
+    }
+  }
+
+  pub fn width(&self) -> usize {
+    self.family().width()
+  }
+
+  pub fn prefix(&self) -> &Prefix {
+    match self {
+      IpPrefix::V4(p) | IpPrefix::V6(p) => p,
+    }
+  }
+
+  pub fn to_cidr_string(&self) -> Result<String> {
+    self.prefix().to_cidr_string(self.family())
+  }
+}
+
+// lives here rather than with Prefix, as the formatting of each family
+// is only known here
+impl Prefix {
+  // e.g. "10.0.0.0/8" for the first 8 bits of 10.0.0.0 in V4. Fails
+  // if the prefix is longer than the family's addresses.
+  pub fn to_cidr_string(&self, family: Family) -> Result<String> {
+    with_family!(family, |T| T::cidr_from_prefix(self.clone()))
+  }
+}
+
+// a CIDR or a single address of either family
+impl FromStr for IpPrefix {
+  type Err = failure::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match Family::detect(s) {
+      Family::V4 => Ok(IpPrefix::V4(parse_range_endpoint::<V4>(s)?)),
+      Family::V6 => Ok(IpPrefix::V6(parse_range_endpoint::<V6>(s)?)),
+    }
+  }
+}
+
+// input with both families, where a "[v4]" or "[v6]" line switches
+// the family of the lines after it. Lines before any marker are v4.
+fn convert_mixed<R: BufRead>(
+  fmt: &Format,
+  opts: &ParseOptions,
+  reader: R,
+) -> Result<String> {
+  let (v4, v6, _) = read_mixed(opts, reader)?;
+  render_mixed(fmt, v4, v6)
+}
+
+// like convert_with, but only the first limit prefixes are output,
+// along with the number left out, e.g. to preview 0.0.0.0/0 minus a
+// few addresses without formatting thousands of prefixes
+pub fn convert_preview(
+  version: &str,
+  fmt: &Format,
+  opts: &ParseOptions,
+  s: &str,
+  limit: usize,
+) -> Result<(String, usize)> {
+  with_family!(version.parse()?, |T| {
+    let tree = App::<T>::read_tree(s.as_bytes(), None, opts)?;
+    App::<T>::render_preview(fmt, tree, limit)
+  })
+}
+
+// like convert with version "mixed", but the v4 and v6 sets are
+// rendered separately
+pub fn convert_dual(sep: &str, s: &str) -> Result<(String, String)> {
+  convert_dual_with(&Format::new(sep), &ParseOptions::default(), s)
+}
+
+pub fn convert_dual_with(
+  fmt: &Format,
+  opts: &ParseOptions,
+  s: &str,
+) -> Result<(String, String)> {
+  let (v4, v6, _) = read_mixed(opts, s.as_bytes())?;
+  Ok((
+    fmt.finish(App::<V4>::render(fmt, v4)?),
+    fmt.finish(App::<V6>::render(fmt, v6)?),
+  ))
+}
+
+// the v4 and v6 sets, and the number of operations applied to them.
+// A ":default" line is for both families, while macros belong to the
+// section they're defined in.
+fn read_mixed<R: BufRead>(
+  opts: &ParseOptions,
+  reader: R,
+) -> Result<(Tree, Tree, usize)> {
+  let mut v4 = Tree::new();
+  let mut v6 = Tree::new();
+  let mut v4_directives = Directives::new(None);
+  let mut v6_directives = Directives::new(None);
+  let mut family = Family::V4;
+  let mut applied = 0;
+
+  for line in opts.lines(reader) {
+    let line = line?;
+    match (line.trim(), family) {
+      ("[v4]", _) => family = Family::V4,
+      ("[v6]", _) => family = Family::V6,
+      (directive, _) if directive.starts_with(":default") => {
+        App::<V4>::parse_line(directive, &mut v4_directives, opts)?;
+        App::<V6>::parse_line(directive, &mut v6_directives, opts)?;
+      }
+      (line, Family::V4) => {
+        let op = App::<V4>::parse_line(line, &mut v4_directives, opts)?;
+        if !matches!(op, TreeOp::Noop) {
+          applied += 1;
+        }
+        v4 = op.apply(v4);
+      }
+      (line, Family::V6) => {
+        let op = App::<V6>::parse_line(line, &mut v6_directives, opts)?;
+        if !matches!(op, TreeOp::Noop) {
+          applied += 1;
+        }
+        v6 = op.apply(v6);
+      }
+    }
+  }
+
+  Ok((v4, v6, applied))
+}
+
+// reads a list of addresses, CIDRs or ranges and outputs the minimal
+// set of CIDRs covering them. Lines without an operator are added.
+// With version "auto", the family is detected per line and the v4
+// set is followed by the v6 set in the output.
+pub fn aggregate<R: BufRead>(
+  version: &str,
+  sep: &str,
+  reader: R,
+) -> Result<String> {
+  let opts = ParseOptions::default();
+  aggregate_with(version, &Format::new(sep), &opts, reader)
+}
+
+pub fn aggregate_with<R: BufRead>(
+  version: &str,
+  fmt: &Format,
+  opts: &ParseOptions,
+  reader: R,
+) -> Result<String> {
+  match version {
+    "auto" => aggregate_auto(fmt, opts, reader),
+    _ => with_family!(version.parse()?, |T| {
+      App::<T>::aggregate(fmt, opts, reader)
+    }),
+  }
+}
+
+// a ":default" line is for both families, a ":def" line for the
+// family of its operands, and a macro for the family it was defined
+// in. Any other line is for the family it looks like.
+fn aggregate_auto<R: BufRead>(
+  fmt: &Format,
+  opts: &ParseOptions,
+  reader: R,
+) -> Result<String> {
+  let mut v4 = Tree::new();
+  let mut v6 = Tree::new();
+  let mut v4_directives = Directives::new(Some(TreeOp::Add));
+  let mut v6_directives = Directives::new(Some(TreeOp::Add));
+
+  for line in opts.lines(reader) {
+    let line = line?;
+    let trimmed = line.trim();
+    let family = match trimmed.strip_prefix(":def ") {
+      _ if trimmed.starts_with(":default") => None,
+      Some(def) => {
+        let operands =
+          def.split_once('=').map_or(def, |(_, operands)| operands);
+        Some(Family::detect(operands))
+      }
+      None if matches!(v6_directives.macro_op(trimmed), Ok(Some(_))) => {
+        Some(Family::V6)
+      }
+      None => Some(Family::detect(trimmed)),
+    };
+
+    if family != Some(Family::V6) {
+      v4 = App::<V4>::parse_line(&line, &mut v4_directives, opts)?.apply(v4);
+    }
+    if family != Some(Family::V4) {
+      v6 = App::<V6>::parse_line(&line, &mut v6_directives, opts)?.apply(v6);
+    }
+  }
+
+  render_mixed(fmt, v4, v6)
+}
+
+// aggregates the routes of RPSL objects, e.g. a whois dump, taken
+// from their "route:" (v4) and "route6:" (v6) attributes. All other
+// attributes are ignored.
+pub fn convert_rpsl<R: BufRead>(sep: &str, reader: R) -> Result<String> {
+  convert_rpsl_with(&Format::new(sep), &ParseOptions::default(), reader)
+}
+
+pub fn convert_rpsl_with<R: BufRead>(
+  fmt: &Format,
+  opts: &ParseOptions,
+  reader: R,
+) -> Result<String> {
+  let mut v4 = Tree::new();
+  let mut v6 = Tree::new();
+
+  for line in opts.lines(reader) {
+    let line = line?;
+    let (attr, value) = match line.split_once(':') {
+      Some((attr, value)) => (attr.trim().to_ascii_lowercase(), value.trim()),
+      None => continue,
+    };
+    match attr.as_str() {
+      "route" => Operand::<V4>::parse_with(value, opts.strict)?.add_to(&mut v4),
+      "route6" => {
+        Operand::<V6>::parse_with(value, opts.strict)?.add_to(&mut v6)
+      }
+      _ => (),
+    }
+  }
+
+  render_mixed(fmt, v4, v6)
+}
+
+// aggregates the ranges of a CSV such as a GeoIP database, one per
+// row, with the start and end address in the given columns (from 0).
+// The addresses may be dotted or colon notation, or 32-bit integers
+// for v4. A first row that isn't a range is taken as a header.
+pub fn convert_csv_ranges<R: BufRead>(
+  sep: &str,
+  start_col: usize,
+  end_col: usize,
+  reader: R,
+) -> Result<String> {
+  let fmt = Format::new(sep);
+  let opts = ParseOptions::default();
+  convert_csv_ranges_with(&fmt, &opts, start_col, end_col, reader)
+}
+
+pub fn convert_csv_ranges_with<R: BufRead>(
+  fmt: &Format,
+  opts: &ParseOptions,
+  start_col: usize,
+  end_col: usize,
+  reader: R,
+) -> Result<String> {
+  let mut v4 = Tree::new();
+  let mut v6 = Tree::new();
+
+  for (i, line) in opts.lines(reader).enumerate() {
+    let line = line?;
+    if line.trim().is_empty() {
+      continue;
+    }
+
+    let fields = split_csv_row(&line);
+    let field = |col: usize| {
+      fields
+        .get(col)
+        .map(|f| f.trim())
+        .ok_or_else(|| format_err!("Line {}: no column {}", i + 1, col))
+    };
+    let range = format!("{}-{}", field(start_col)?, field(end_col)?);
+    let family = Family::detect(&range);
+    match with_family!(family, |T| parse_range_operand::<T>(&range)) {
+      Ok(tree) if family == Family::V4 => v4 = v4.add_tree(tree),
+      Ok(tree) => v6 = v6.add_tree(tree),
+      Err(_) if i == 0 => continue,
+      Err(err) => return Err(err),
+    }
+  }
+
+  render_mixed(fmt, v4, v6)
+}
+
+// the fields of a CSV row, where a field in double quotes may contain
+// commas and "" for a quote
+fn split_csv_row(line: &str) -> Vec<String> {
+  let mut fields = vec![];
+  let mut field = String::new();
+  let mut quoted = false;
+  let mut chars = line.chars().peekable();
+
+  while let Some(c) = chars.next() {
+    match c {
+      '"' if quoted && chars.peek() == Some(&'"') => {
+        field.push('"');
+        chars.next();
+      }
+      '"' => quoted = !quoted,
+      ',' if !quoted => fields.push(std::mem::take(&mut field)),
+      c => field.push(c),
+    }
+  }
+  fields.push(field);
+  fields
+}
+
+// the v4 set followed by the v6 set
+fn render_mixed(fmt: &Format, v4: Tree, v6: Tree) -> Result<String> {
+  let outputs = [App::<V4>::render(fmt, v4)?, App::<V6>::render(fmt, v6)?];
+  let out = outputs
+    .into_iter()
+    .filter(|s| !s.is_empty())
+    .collect::<Vec<_>>()
+    .join(&fmt.sep);
+  Ok(fmt.finish(out))
+}
+
+// applies a single line of rules to the set
+pub fn apply_line(version: &str, tree: Tree, line: &str) -> Result<Tree> {
+  with_family!(version.parse()?, |T| {
+    Ok(TreeOp::<T>::parse(line.trim())?.apply(tree))
+  })
+}
+
+// renders a set the same way as convert
+pub fn render(version: &str, sep: &str, tree: Tree) -> Result<String> {
+  let fmt = Format::new(sep);
+  with_family!(version.parse()?, |T| App::<T>::render(&fmt, tree))
+}
+
+// runs the rules like convert, but returns the resulting set rather
+// than rendering it
+pub fn build_tree(version: &str, s: &str) -> Result<Tree> {
+  build_tree_with(version, &ParseOptions::default(), s)
+}
+
+pub fn build_tree_with(
+  version: &str,
+  opts: &ParseOptions,
+  s: &str,
+) -> Result<Tree> {
+  with_family!(version.parse()?, |T| {
+    App::<T>::read_tree(s.as_bytes(), None, opts)
+  })
+}
+
+// like build_tree, but skips the lines that fail to parse instead of
+// failing on the first one. Returns the set built from the other
+// lines, along with each skipped line's number (from 1) and error.
+pub fn build_tree_lossy(
+  version: &str,
+  s: &str,
+) -> Result<(Tree, Vec<(usize, failure::Error)>)> {
+  build_tree_lossy_with(version, &ParseOptions::default(), s)
+}
+
+pub fn build_tree_lossy_with(
+  version: &str,
+  opts: &ParseOptions,
+  s: &str,
+) -> Result<(Tree, Vec<(usize, failure::Error)>)> {
+  with_family!(version.parse()?, |T| {
+    App::<T>::read_tree_lossy(s.as_bytes(), opts)
+  })
+}
+
+// the number of prefixes convert would output, without rendering them
+pub fn count_prefixes(version: &str, s: &str) -> Result<usize> {
+  count_prefixes_with(version, &ParseOptions::default(), s)
+}
+
+pub fn count_prefixes_with(
+  version: &str,
+  opts: &ParseOptions,
+  s: &str,
+) -> Result<usize> {
+  Ok(build_tree_with(version, opts, s)?.count_prefixes())
+}
+
+// the number of addresses the rules cover under each /8 for v4, or
+// each /16 for v6, e.g. ("10.0.0.0/8", 65536). Blocks with nothing
+// covered are left out.
+pub fn density_report(version: &str, s: &str) -> Result<Vec<(String, u128)>> {
+  density_report_with(version, &ParseOptions::default(), s)
+}
+
+pub fn density_report_with(
+  version: &str,
+  opts: &ParseOptions,
+  s: &str,
+) -> Result<Vec<(String, u128)>> {
+  let family = version.parse()?;
+  let bucket_len = match family {
+    Family::V4 => 8,
+    Family::V6 => 16,
+  };
+  with_family!(family, |T| {
+    App::<T>::density_report(build_tree_with(version, opts, s)?, bucket_len)
+  })
+}
+
+// like convert, but each output prefix is followed by the input
+// lines that added or deleted addresses within it, e.g.
+// "10.0.0.0/23 <= {+10.0.0.0/24, +10.0.1.0/24}"
+pub fn explain<R: BufRead>(
+  version: &str,
+  sep: &str,
+  reader: R,
+) -> Result<String> {
+  let opts = ParseOptions::default();
+  explain_with(version, &Format::new(sep), &opts, reader)
+}
+
+pub fn explain_with<R: BufRead>(
+  version: &str,
+  fmt: &Format,
+  opts: &ParseOptions,
+  reader: R,
+) -> Result<String> {
+  with_family!(version.parse()?, |T| App::<T>::explain(fmt, opts, reader))
+}
+
+// warnings for operands that expand to more than max_prefixes
+// prefixes, e.g. "10.0.0.1-10.0.0.254" which was probably meant to be
+// 10.0.0.0/24
+pub fn check_ranges<R: BufRead>(
+  version: &str,
+  max_prefixes: usize,
+  reader: R,
+) -> Result<Vec<String>> {
+  let opts = ParseOptions::default();
+  check_ranges_with(version, &opts, max_prefixes, reader)
+}
+
+pub fn check_ranges_with<R: BufRead>(
+  version: &str,
+  opts: &ParseOptions,
+  max_prefixes: usize,
+  reader: R,
+) -> Result<Vec<String>> {
+  with_family!(version.parse()?, |T| {
+    App::<T>::check_ranges(opts, reader, max_prefixes)
+  })
+}
+
+// the supernets synthesized by aggregation, see App::supernets
+pub fn supernets<R: BufRead>(
+  version: &str,
+  sep: &str,
+  reader: R,
+) -> Result<String> {
+  let opts = ParseOptions::default();
+  supernets_with(version, &Format::new(sep), &opts, reader)
+}
+
+pub fn supernets_with<R: BufRead>(
+  version: &str,
+  fmt: &Format,
+  opts: &ParseOptions,
+  reader: R,
+) -> Result<String> {
+  with_family!(version.parse()?, |T| App::<T>::supernets(fmt, opts, reader))
+}
+
+// the surviving prefixes in the order they were added, see
+// App::as_entered
+pub fn as_entered<R: BufRead>(
+  version: &str,
+  sep: &str,
+  reader: R,
+) -> Result<String> {
+  let opts = ParseOptions::default();
+  as_entered_with(version, &Format::new(sep), &opts, reader)
+}
+
+pub fn as_entered_with<R: BufRead>(
+  version: &str,
+  fmt: &Format,
+  opts: &ParseOptions,
+  reader: R,
+) -> Result<String> {
+  with_family!(version.parse()?, |T| App::<T>::as_entered(
+    fmt, opts, reader
+  ))
+}
+
+// outputs the free space within the universe (a CIDR or range) that
+// is not used by any of the addresses, CIDRs or ranges read from the
+// input. Like in aggregate, lines without an operator are added.
+pub fn free<R: BufRead>(
+  sep: &str,
+  universe: &str,
+  reader: R,
+) -> Result<String> {
+  let opts = ParseOptions::default();
+  free_with(&Format::new(sep), &opts, universe, reader)
+}
+
+pub fn free_with<R: BufRead>(
+  fmt: &Format,
+  opts: &ParseOptions,
+  universe: &str,
+  reader: R,
+) -> Result<String> {
+  with_family!(Family::detect(universe), |T| {
+    App::<T>::free(fmt, opts, universe, reader)
+  })
+}
+
+// the CIDRs covering a single range, e.g. "10.0.0.1-10.0.0.3" gives
+// 10.0.0.1/32 and 10.0.0.2/31
+pub fn explain_range(sep: &str, range: &str) -> Result<String> {
+  let fmt = Format::new(sep);
+  with_family!(Family::detect(range), |T| {
+    App::<T>::render(&fmt, parse_range_operand::<T>(range)?)
+  })
+}
+
+// how a range fits in a budget of max_prefixes CIDRs, e.g. route
+// entries in a BGP filter
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeFit {
+  // the number of CIDRs covering the range exactly
+  pub exact_prefixes: usize,
+  // the exact CIDRs if they fit in the budget, otherwise the fewest
+  // addresses covering the range in the budget
+  pub prefixes: Vec<String>,
+  // the number of addresses the prefixes cover outside the range, 0
+  // if it fits
+  pub extra_addresses: u128,
+}
+
+impl RangeFit {
+  pub fn fits(&self) -> bool {
+    self.extra_addresses == 0
+  }
+}
+
+pub fn fit_range(range: &str, max_prefixes: usize) -> Result<RangeFit> {
+  with_family!(Family::detect(range), |T| {
+    App::<T>::fit_range(range, max_prefixes)
+  })
+}
+
+// for several scenarios in one input, separated by "---" lines, each
+// converted on its own and output under a "--- LABEL" header. The
+// label is the text after the "---", by default "section N".
+pub fn convert_sections<R: BufRead>(
+  version: &str,
+  sep: &str,
+  reader: R,
+) -> Result<String> {
+  let opts = ParseOptions::default();
+  convert_sections_with(version, &Format::new(sep), &opts, reader)
+}
+
+pub fn convert_sections_with<R: BufRead>(
+  version: &str,
+  fmt: &Format,
+  opts: &ParseOptions,
+  reader: R,
+) -> Result<String> {
+  with_family!(version.parse()?, |T| App::<T>::convert_sections(
+    fmt, opts, reader
+  ))
+}
+
+// everything but a single range, within the universe (a CIDR or
+// range) if given, otherwise within the whole address space. E.g. a
+// blocklist around a carve-out.
+pub fn exclude_range(
+  sep: &str,
+  range: &str,
+  universe: Option<&str>,
+) -> Result<String> {
+  let fmt = Format::new(sep);
+  with_family!(Family::detect(range), |T| {
+    App::<T>::exclude_range(&fmt, range, universe)
+  })
+}
+
+fn parse_range_operand<T: AddrType>(s: &str) -> Result<Tree> {
+  T::parse_range(s).map_err(|e| {
+    NetcalcError::InvalidRange(s.to_string(), e.to_string()).into()
+  })
+}
+
+// what check_host_bits found about a CIDR line
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostBitsFinding {
+  // from 1
+  pub line: usize,
+  // the CIDR with the host bits cleared, e.g. 10.0.0.0/24 for
+  // 10.0.0.5/24
+  pub normalized: String,
+  pub host_bits_set: bool,
+}
+
+// checks each CIDR line for host bits set, e.g. to hint "did you
+// mean 10.0.0.0/24?" for 10.0.0.5/24 in a form, without building the
+// set. Unlike the strict flag this doesn't stop at the first one.
+pub fn check_host_bits<R: BufRead>(
+  version: &str,
+  reader: R,
+) -> Result<Vec<HostBitsFinding>> {
+  check_host_bits_with(version, &ParseOptions::default(), reader)
+}
+
+pub fn check_host_bits_with<R: BufRead>(
+  version: &str,
+  opts: &ParseOptions,
+  reader: R,
+) -> Result<Vec<HostBitsFinding>> {
+  with_family!(version.parse()?, |T| {
+    App::<T>::check_host_bits(opts, reader)
+  })
+}
+
+// returns the first (network) and last (broadcast) address of a CIDR
+pub fn cidr_range(version: &str, s: &str) -> Result<(String, String)> {
+  with_family!(version.parse()?, |T| App::<T>::cidr_range(s))
+}
+
+// the number of usable host addresses in a prefix of len bits, where
+// the whole space is total_bits long. In IPv4 the network and
+// broadcast addresses aren't usable, except in a /31 point-to-point
+// link (RFC 3021) and a /32. IPv6 has no broadcast, so all addresses
+// count. Saturates at u128::MAX for ::/0, and is 0 when len is longer
+// than total_bits.
+pub fn hosts_in_prefix(len: u32, total_bits: u32) -> u128 {
+  let addrs = subnets_of_length(len, total_bits);
+  if total_bits == 32 && addrs > 2 {
+    addrs - 2
+  } else {
+    addrs
+  }
+}
+
+// the number of prefixes of child_len bits within one of parent_len
+// bits, e.g. 4 /26s in a /24. Saturates at u128::MAX, and is 0 when
+// the child is shorter than the parent.
+pub fn subnets_of_length(parent_len: u32, child_len: u32) -> u128 {
+  match child_len.checked_sub(parent_len) {
+    Some(diff) => 1u128.checked_shl(diff).unwrap_or(u128::MAX),
+    None => 0,
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  // convert_with with the default parse options
+  fn convert_fmt(version: &str, fmt: &Format, s: &str) -> Result<String> {
+    convert_with(version, fmt, &ParseOptions::default(), s)
+  }
+
+  #[test]
+  fn test_cidr_range() {
+    assert_eq!(
+      cidr_range("v4", "10.0.0.0/22").unwrap(),
+      ("10.0.0.0".into(), "10.0.3.255".into())
+    );
+    assert_eq!(
+      cidr_range("v6", "2001:db8::/32").unwrap(),
+      (
+        "2001:db8::".into(),
+        "2001:db8:ffff:ffff:ffff:ffff:ffff:ffff".into()
+      )
+    );
+  }
+
+  #[test]
+  fn test_aggregate() {
+    let input =
+      "10.0.0.0/24\n+10.0.1.0/24\n2001:db8::/33\n\n2001:db8:8000::/33";
+    assert_eq!(
+      aggregate("auto", ",", input.as_bytes()).unwrap(),
+      "10.0.0.0/23,2001:db8::/32"
+    );
+
+    let input = "10.0.0.0/24\n10.0.1.0/24\n-10.0.0.0/25";
+    assert_eq!(
+      aggregate("v4", ",", input.as_bytes()).unwrap(),
+      "10.0.0.128/25,10.0.1.0/24"
+    );
+    assert!(aggregate("v4", ",", "2001:db8::/32".as_bytes()).is_err());
+  }
+
+  #[test]
+  fn test_trailing_sep() {
+    let fmt = Format {
+      trailing_sep: true,
+      ..Format::new("\n")
+    };
+    assert_eq!(
+      convert_fmt("v4", &fmt, "+10.0.0.0/8\n+192.168.0.0/16").unwrap(),
+      "10.0.0.0/8\n192.168.0.0/16\n"
+    );
+    assert_eq!(convert_fmt("v4", &fmt, "# nothing").unwrap(), "");
+    assert_eq!(
+      convert("v4", "\n", "+10.0.0.0/8\n+192.168.0.0/16").unwrap(),
+      "10.0.0.0/8\n192.168.0.0/16"
+    );
+  }
+
+  #[test]
+  fn test_mixed_families() {
+    let input = "+10.0.0.0/8\n[v6]\n+2001:db8::/32\n[v4]\n-10.0.0.0/9";
+    assert_eq!(
+      convert("mixed", ",", input).unwrap(),
+      "10.128.0.0/9,2001:db8::/32"
+    );
+    assert!(convert("mixed", ",", "[v6]\n+10.0.0.0/8").is_err());
+
+    assert_eq!(
+      convert_dual(",", input).unwrap(),
+      ("10.128.0.0/9".to_string(), "2001:db8::/32".to_string())
+    );
+    assert_eq!(
+      convert_dual(",", "+10.0.0.0/8").unwrap(),
+      ("10.0.0.0/8".to_string(), "".to_string())
+    );
+  }
+
+  #[test]
+  fn test_word_operators() {
+    let words = "add 10.0.0.0/8\nDEL 10.1.0.0/16\nNoop 10.2.0.0/16";
+    let sigils = "+10.0.0.0/8\n-10.1.0.0/16";
+    assert_eq!(
+      convert("v4", ",", words).unwrap(),
+      convert("v4", ",", sigils).unwrap()
+    );
+    assert_eq!(
+      aggregate("v4", ",", "add 10.0.0.0/8".as_bytes()).unwrap(),
+      "10.0.0.0/8"
+    );
+    assert!(convert("v4", ",", "add").is_err());
+  }
+
+  #[test]
+  fn test_free() {
+    let used = "10.0.0.0/24\n10.0.2.0/23\n192.168.0.0/16";
+    assert_eq!(
+      free(",", "10.0.0.0/22", used.as_bytes()).unwrap(),
+      "10.0.1.0/24"
+    );
+    assert_eq!(
+      free(",", "2001:db8::/32", "+2001:db8::/33".as_bytes()).unwrap(),
+      "2001:db8:8000::/33"
+    );
+  }
+
+  #[test]
+  fn test_hex_prefix_len() {
+    assert_eq!(
+      convert("v4", ",", "+10.0.0.0/0x18\n+10.0.1.0/0X18").unwrap(),
+      "10.0.0.0/23"
+    );
+    assert_eq!(
+      convert("v6", ",", "+2001:db8::/0x20").unwrap(),
+      "2001:db8::/32"
+    );
+    assert!(convert("v4", ",", "+10.0.0.0/0x21").is_err());
+    assert!(convert("v6", ",", "+2001:db8::/0x81").is_err());
+    assert!(convert("v4", ",", "+10.0.0.0/0x").is_err());
+    assert!(convert("v4", ",", "+10.0.0.0/0b11").is_err());
+  }
+
+  #[test]
+  fn test_explain() {
+    let input = "+10.0.0.0/24\n+10.0.1.0/24\n+10.0.2.0/23\n+192.168.0.0/24\n-10.0.3.0/24\n+10.0.3.0/24";
+    assert_eq!(
+      explain("v4", "\n", input.as_bytes()).unwrap(),
+      "10.0.0.0/22 <= {+10.0.0.0/24, +10.0.1.0/24, +10.0.2.0/23, +10.0.3.0/24}\n\
+       192.168.0.0/24 <= {+192.168.0.0/24}"
+    );
+
+    // an addition entirely overridden later isn't a source
+    let input = "+10.0.0.0/24\n+10.0.1.0/24\n-10.0.1.0/24\n=10.0.0.0/24\n\
+                 +10.0.0.0/25";
+    assert_eq!(
+      explain("v4", "\n", input.as_bytes()).unwrap(),
+      "10.0.0.0/24 <= {=10.0.0.0/24, +10.0.0.0/25}"
+    );
+  }
+
+  #[test]
+  fn test_operator_whitespace() {
+    assert_eq!(
+      convert("v4", ",", "+ 10.0.0.0/8\n-\t10.0.0.0/9\nadd  10.0.0.0/9")
+        .unwrap(),
+      "10.0.0.0/8"
+    );
+    assert_eq!(convert("v4", ",", "+10.0.0.0/8\n- 10.0.0.0/8").unwrap(), "");
+    assert_eq!(
+      convert("v4", ",", "add\t10.0.0.0/8\ndel \t10.0.0.0/9").unwrap(),
+      "10.128.0.0/9"
+    );
+  }
+
+  #[test]
+  fn test_add_only() {
+    let mut state = 1u64;
+    let mut next = || {
+      state ^= state << 13;
+      state ^= state >> 7;
+      state ^= state << 17;
+      state.to_le_bytes()
+    };
+    let mut rules = String::new();
+    for i in 0..1000 {
+      let [a, b, c, d, e, ..] = next();
+      let a = a % 4 + 10;
+      rules.push_str(&match i % 4 {
+        0 => format!("+{}.{}.{}.{}/32\n", a, b, c, d),
+        1 => format!("+{}.{}.{}.0/{}\n", a, b, c, 16 + e % 9),
+        2 => format!("{}.{}.{}.{}-{}.{}.{}.255\n", a, b, c, d, a, b, c),
+        _ => format!("add {}.{}.{}.{}\n# {}\n", a, b, c, d, e),
+      });
+    }
+    let rules = format!(":default +\n{}", rules);
+
+    // a no-op intersection first takes the general path
+    let general = format!("&0.0.0.0/0\n{}", rules);
+    assert_eq!(
+      convert("v4", ",", &rules).unwrap(),
+      convert("v4", ",", &general).unwrap()
+    );
+    assert_eq!(
+      build_tree("v4", &rules).unwrap(),
+      build_tree("v4", &general).unwrap().optimize()
+    );
+
+    // rules that stop being add-only carry on from what was added
+    let rules = "+10.0.0.0/24\n+10.0.1.0/24\n-10.0.1.0/25\n+10.0.2.0/24";
+    assert_eq!(
+      convert("v4", ",", rules).unwrap(),
+      "10.0.0.0/24,10.0.1.128/25,10.0.2.0/24"
+    );
+    let (_, applied) = convert_counted(
+      "v4",
+      &Format::new(","),
+      &ParseOptions::default(),
+      "+10.0.0.0/24\n# comment\n+10.0.1.0/24",
+    )
+    .unwrap();
+    assert_eq!(applied, 2);
+  }
+
+  #[test]
+  fn test_build_tree() {
+    let input = "+10.0.0.0/8\n-10.128.0.0/9\n+192.168.0.1";
+    let tree = build_tree("v4", input).unwrap();
+
+    let cidrs = tree
+      .prefixes()
+      .into_iter()
+      .map(V4::cidr_from_prefix)
+      .collect::<Result<Vec<_>>>()
+      .unwrap();
+    assert_eq!(cidrs.join(","), convert("v4", ",", input).unwrap());
+    assert!(build_tree("mixed", input).is_err());
+  }
+
+  #[test]
+  fn test_count_prefixes() {
+    let input = "+10.0.0.0/8\n-10.128.0.0/9\n+192.168.0.1\n+10.0.0.0/24";
+    let output = convert("v4", ",", input).unwrap();
+    assert_eq!(count_prefixes("v4", input).unwrap(), 2);
+    assert_eq!(output.split(',').count(), 2);
+
+    assert_eq!(count_prefixes("v6", "").unwrap(), 0);
+    assert!(count_prefixes("v5", "").is_err());
+  }
+
+  #[test]
+  fn test_complement_operand() {
+    assert_eq!(
+      convert("v4", ",", "+!10.0.0.0/8").unwrap(),
+      "0.0.0.0/5,8.0.0.0/7,11.0.0.0/8,12.0.0.0/6,16.0.0.0/4,32.0.0.0/3,64.0.0.0/2,128.0.0.0/1"
+    );
+    assert_eq!(
+      convert("v4", ",", "+0.0.0.0/0\n-~10.0.0.0/8").unwrap(),
+      "10.0.0.0/8"
+    );
+    assert_eq!(convert("v6", ",", "+!!::1").unwrap(), "::1/128");
+  }
+
+  #[test]
+  fn test_grouped() {
+    let fmt = Format {
+      group_len: Some(8),
+      ..Format::new(",")
+    };
+    let input = "+10.1.0.0/16\n+10.2.0.0/16\n+11.0.0.0/8\n+12.0.0.1\n+12.0.0.3";
+    assert_eq!(
+      convert_fmt("v4", &fmt, input).unwrap(),
+      "10.0.0.0/8\n  10.1.0.0/16\n  10.2.0.0/16\n11.0.0.0/8\n\
+       12.0.0.0/8\n  12.0.0.1/32\n  12.0.0.3/32"
+    );
+
+    // the trailing separator is a newline too, not the sep
+    let fmt = Format {
+      trailing_sep: true,
+      ..fmt
+    };
+    assert_eq!(
+      convert_fmt("v4", &fmt, "+10.1.0.0/16").unwrap(),
+      "10.0.0.0/8\n  10.1.0.0/16\n"
+    );
+  }
+
+  #[test]
+  fn test_intersect_and_set() {
+    let input = "+10.0.0.0/8\n&10.1.0.0/16\n+192.168.0.0/16";
+    assert_eq!(
+      convert("v4", ",", input).unwrap(),
+      "10.1.0.0/16,192.168.0.0/16"
+    );
+    let input = "+10.0.0.0/8\n= 172.16.0.0/12\n-172.16.0.0/13";
+    assert_eq!(convert("v4", ",", input).unwrap(), "172.24.0.0/13");
+  }
+
+  #[test]
+  fn test_apply_line() {
+    let tree = apply_line("v4", Tree::new(), "+10.0.0.0/8").unwrap();
+    let tree = apply_line("v4", tree, " -10.0.0.0/9 ").unwrap();
+    assert_eq!(render("v4", ",", tree.clone()).unwrap(), "10.128.0.0/9");
+    assert!(apply_line("v4", tree, "+::1").is_err());
+  }
+
+  #[test]
+  fn test_full_range() {
+    assert_eq!(
+      convert("v4", ",", "+0.0.0.0-255.255.255.255").unwrap(),
+      "0.0.0.0/0"
+    );
+    assert_eq!(
+      convert("v6", ",", "+::-ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")
+        .unwrap(),
+      "::/0"
+    );
+  }
+
+  #[test]
+  fn test_sep_escapes() {
+    let input = "+10.0.0.0/8\n+192.168.0.0/16";
+    assert_eq!(
+      convert("v4", "\\t", input).unwrap(),
+      "10.0.0.0/8\t192.168.0.0/16"
+    );
+    assert_eq!(
+      convert("v4", "\\r\\n", input).unwrap(),
+      "10.0.0.0/8\r\n192.168.0.0/16"
+    );
+    assert_eq!(
+      convert("v4", "\\\\n", input).unwrap(),
+      "10.0.0.0/8\\n192.168.0.0/16"
+    );
+    assert_eq!(
+      convert("v4", " \\x ", input).unwrap(),
+      "10.0.0.0/8 \\x 192.168.0.0/16"
+    );
+  }
+
+  #[test]
+  fn test_default_directive() {
+    let input =
+      ":default +\n10.0.0.0/8\n-10.0.0.0/9\n:default -\n10.192.0.0/10";
+    assert_eq!(convert("v4", ",", input).unwrap(), "10.128.0.0/10");
+
+    let input = ":default +\n10.0.0.0/8\n:default none\n10.0.0.0/8";
+    assert!(convert("v4", ",", input).is_err());
+    assert!(convert("v4", ",", "10.0.0.0/8").is_err());
+    assert!(convert("v4", ",", ":default *").is_err());
+
+    // the other readers take the directives too
+    let input = ":default +\n:def lab = 10.1.0.0/24\n10.0.0.0/24\nlab";
+    assert_eq!(
+      explain("v4", ",", input.as_bytes()).unwrap(),
+      "10.0.0.0/24 <= {10.0.0.0/24},10.1.0.0/24 <= {lab}"
+    );
+    assert_eq!(
+      as_entered("v4", ",", input.as_bytes()).unwrap(),
+      "10.0.0.0/24,10.1.0.0/24"
+    );
+    let input = ":default +\n10.0.0.0/24\n10.0.1.0/24";
+    assert_eq!(
+      supernets("v4", ",", input.as_bytes()).unwrap(),
+      "10.0.0.0/23 <= 10.0.0.0/24, 10.0.1.0/24"
+    );
+    let input =
+      ":default +\n:def r = 10.0.0.1-10.0.0.254\nr\n10.0.0.1-10.0.0.6";
+    assert_eq!(
+      check_ranges("v4", 2, input.as_bytes()).unwrap(),
+      vec!["line 4: 10.0.0.1-10.0.0.6 expands to 4 prefixes, more than 2"]
+    );
+
+    // in mixed input, ":default" is for both families
+    let input = ":default +\n10.0.0.0/8\n[v6]\n:def doc = 2001:db8::/32\ndoc";
+    assert_eq!(
+      convert("mixed", ",", input).unwrap(),
+      "10.0.0.0/8,2001:db8::/32"
+    );
+    let input = ":def doc = 2001:db8::/32\n:def lab = 10.1.0.0/16\n\
+                 10.0.0.0/8\n2001:db8::/31\n:default -\ndoc\nlab";
+    assert_eq!(
+      aggregate("auto", ",", input.as_bytes()).unwrap(),
+      "10.0.0.0/16,10.2.0.0/15,10.4.0.0/14,10.8.0.0/13,10.16.0.0/12,\
+       10.32.0.0/11,10.64.0.0/10,10.128.0.0/9,2001:db9::/32"
+    );
+  }
+
+  #[test]
+  fn test_indentation() {
+    let input =
+      "  # a comment\n\t+10.0.0.0/8\n    -10.0.0.0/9\n\t# +11.0.0.0/8";
+    assert_eq!(convert("v4", ",", input).unwrap(), "10.128.0.0/9");
+    assert_eq!(
+      apply_line("v4", Tree::new(), "   #+10.0.0.0/8").unwrap(),
+      Tree::new()
+    );
+    assert_eq!(
+      convert("v6", ",", " +2001:db8::1\n  #2001:db8::2").unwrap(),
+      "2001:db8::1/128"
+    );
+    // a multi-byte first character must not panic
+    assert!(convert("v4", ",", "é10.0.0.0/8").is_err());
+  }
+
+  #[test]
+  fn test_error_code() {
+    let code = |s| NetcalcError::code_of(&convert("v4", ",", s).unwrap_err());
+    assert_eq!(code("+10.0.0.0/33"), "invalid_cidr");
+    assert_eq!(code("+10.0.0/8"), "invalid_cidr");
+    assert_eq!(code("+10.0.0.256"), "invalid_address");
+    assert_eq!(code("+10.0.0.2-10.0.0.1"), "invalid_range");
+    assert_eq!(code("+10.*.*.*.1"), "invalid_wildcard");
+    assert_eq!(code("10.0.0.0/8"), "unrecognized_line");
+
+    let err = convert("v5", ",", "").unwrap_err();
+    assert_eq!(NetcalcError::code_of(&err), "unrecognized_version");
+  }
+
+  #[test]
+  fn test_cidr_endpoint_range() {
+    let fmt = Format::new(",");
+    let strict = ParseOptions {
+      strict: true,
+      ..ParseOptions::default()
+    };
+
+    let aligned = "+10.0.0.0/24 - 10.0.1.0/24";
+    assert_eq!(convert("v4", ",", aligned).unwrap(), "10.0.0.0/23");
+    assert_eq!(
+      convert_with("v4", &fmt, &strict, aligned).unwrap(),
+      "10.0.0.0/23"
+    );
+
+    // snapped to the block boundaries unless strict
+    let sloppy = "+10.0.0.5/24 - 10.0.1.5/24";
+    assert_eq!(convert("v4", ",", sloppy).unwrap(), "10.0.0.0/23");
+    let err = convert_with("v4", &fmt, &strict, sloppy).unwrap_err();
+    assert_eq!(NetcalcError::code_of(&err), "invalid_range");
+    assert!(
+      convert_with("v4", &fmt, &strict, "+10.0.0.0/24-10.0.1.255/24").is_err()
+    );
+    assert!(convert_with("v4", &fmt, &strict, "+10.0.0.0/24-10.0.1.5").is_ok());
+
+    assert_eq!(
+      convert("v6", ",", "+2001:db8::/48-2001:db8:1::/48").unwrap(),
+      "2001:db8::/47"
+    );
+  }
+
+  #[test]
+  fn test_strict_comments() {
+    let fmt = Format::new(",");
+    let strict = ParseOptions {
+      strict: true,
+      ..ParseOptions::default()
+    };
+    let rules = "# allocations\n+10.0.0.0/8\n\n";
+    assert_eq!(convert("v4", ",", rules).unwrap(), "10.0.0.0/8");
+
+    let err = convert_with("v4", &fmt, &strict, rules).unwrap_err();
+    assert_eq!(NetcalcError::code_of(&err), "unrecognized_line");
+    assert_eq!(
+      convert_with("v4", &fmt, &strict, "+10.0.0.0/8\n\nnoop").unwrap(),
+      "10.0.0.0/8"
+    );
+  }
+
+  #[test]
+  fn test_explain_range() {
+    assert_eq!(
+      explain_range(", ", "10.0.0.1-10.0.0.10").unwrap(),
+      "10.0.0.1/32, 10.0.0.2/31, 10.0.0.4/30, 10.0.0.8/31, 10.0.0.10/32"
+    );
+    assert_eq!(
+      explain_range(",", "2001:db8::-2001:db8::1").unwrap(),
+      "2001:db8::/127"
+    );
+
+    let err = explain_range(",", "10.0.0.0/8").unwrap_err();
+    assert_eq!(NetcalcError::code_of(&err), "invalid_range");
+  }
+
+  #[test]
+  fn test_check_ranges() {
+    let rules = "+10.0.0.1-10.0.0.254\n+10.1.0.0-10.1.0.255\n+10.2.0.0/24";
+    assert_eq!(
+      check_ranges("v4", 8, rules.as_bytes()).unwrap(),
+      vec!["line 1: +10.0.0.1-10.0.0.254 expands to 14 prefixes, more than 8"]
+    );
+    assert!(check_ranges("v4", 14, rules.as_bytes()).unwrap().is_empty());
+
+    // the count is all that's checked, whatever the operand
+    assert_eq!(
+      check_ranges("v4", 4, "-!10.0.0.0/8".as_bytes()).unwrap(),
+      vec!["line 1: -!10.0.0.0/8 expands to 8 prefixes, more than 4"]
+    );
+  }
+
+  #[test]
+  fn test_rpsl() {
+    let dump = "\
+route:          10.0.0.0/24
+descr:          example
+origin:         AS64500
+mnt-by:         MAINT-EXAMPLE
+
+route:          10.0.1.0/24
+origin:         AS64500
+
+Route6:         2001:db8::/32
+origin:         AS64500
+";
+    assert_eq!(
+      convert_rpsl(",", dump.as_bytes()).unwrap(),
+      "10.0.0.0/23,2001:db8::/32"
+    );
+    assert!(convert_rpsl(",", "route: 2001:db8::/32".as_bytes()).is_err());
+  }
+
+  #[test]
+  fn test_supernets() {
+    let rules = "+10.0.0.0/24\n+10.0.1.0/24\n+192.168.0.0/24\n\
+                 +172.16.0.0/25\n+172.16.0.128/25\n-172.16.0.0/32";
+    assert_eq!(
+      supernets("v4", "\n", rules.as_bytes()).unwrap(),
+      "10.0.0.0/23 <= 10.0.0.0/24, 10.0.1.0/24"
+    );
+    assert_eq!(supernets("v4", "\n", "+10.0.0.0/8".as_bytes()).unwrap(), "");
+
+    // the inputs in the order they were entered
+    let rules = "+10.0.1.0/24\n+10.0.0.0/24\n+10.0.3.128/25\n+10.0.2.0/24\n\
+                 +10.0.3.0/25\n+10.1.0.0/16\n+10.1.0.0/24";
+    assert_eq!(
+      supernets("v4", "\n", rules.as_bytes()).unwrap(),
+      "10.0.0.0/22 <= 10.0.1.0/24, 10.0.0.0/24, 10.0.3.128/25, \
+       10.0.2.0/24, 10.0.3.0/25"
+    );
+  }
+
+  #[test]
+  fn test_as_entered() {
+    let rules = "+192.168.0.0/24\n+10.0.0.0/8\n+10.1.0.0/16\n\
+                 +172.16.0.0/24\n-172.16.0.0/24\n+192.168.1.0/24\n\
+                 -10.0.0.0/9";
+    assert_eq!(
+      as_entered("v4", ",", rules.as_bytes()).unwrap(),
+      "192.168.0.0/24,10.128.0.0/9,192.168.1.0/24"
+    );
+    // not aggregated, unlike convert
+    assert_eq!(convert("v4", ",", rules).unwrap().split(',').count(), 2);
+
+    assert_eq!(
+      as_entered("v4", ",", "+10.0.0.1\n+10.0.0.0/31".as_bytes()).unwrap(),
+      "10.0.0.1/32,10.0.0.0/32"
+    );
+  }
+
+  #[test]
+  fn test_column_range() {
+    assert_eq!(
+      build_tree("v4", "+10.0.0.1\t10.0.0.5").unwrap(),
+      build_tree("v4", "+10.0.0.1-10.0.0.5").unwrap()
+    );
+    assert_eq!(
+      aggregate("v4", ",", "10.0.0.0  10.0.0.255\n\t10.0.1.0".as_bytes())
+        .unwrap(),
+      "10.0.0.0/24,10.0.1.0/32"
+    );
+
+    let code = |s| NetcalcError::code_of(&convert("v4", ",", s).unwrap_err());
+    assert_eq!(code("+10.0.0.5\t10.0.0.1"), "invalid_range");
+    assert_eq!(code("+10.0.0.0/8\t10.0.0.1"), "invalid_cidr");
+  }
+
+  #[test]
+  fn test_any_keyword() {
+    assert_eq!(convert("v4", ",", "+any").unwrap(), "0.0.0.0/0");
+    assert_eq!(convert("v6", ",", "+default").unwrap(), "::/0");
+    assert_eq!(
+      convert("v4", ",", "+ALL\n-0.0.0.0/1").unwrap(),
+      "128.0.0.0/1"
+    );
+    assert_eq!(convert("v4", ",", "+10.0.0.0/8\n-any").unwrap(), "");
+  }
+
+  #[test]
+  fn test_metadata() {
+    let input = "10.0.0.0/8 | AS64500 | corp\n11.0.0.0/8 | AS64501";
+    assert_eq!(
+      aggregate("v4", ",", input.as_bytes()).unwrap(),
+      "10.0.0.0/7"
+    );
+    assert_eq!(
+      convert("v4", ",", "+10.0.0.0/8  | AS64500\n-10.1.0.0/16 | x").unwrap(),
+      convert("v4", ",", "+10.0.0.0/8\n-10.1.0.0/16").unwrap()
+    );
+
+    // only a | surrounded by spaces starts the metadata
+    let err = convert("v4", ",", "+10.0.0.0/8|x").unwrap_err();
+    assert_eq!(NetcalcError::code_of(&err), "invalid_cidr");
+  }
+
+  #[test]
+  fn test_ip_prefix() {
+    let v4: IpPrefix = "10.1.2.3/8".parse().unwrap();
+    assert_eq!(v4.family(), Family::V4);
+    assert_eq!(v4.width(), 32);
+    assert_eq!(v4.prefix().len(), 8);
+    assert_eq!(v4.to_cidr_string().unwrap(), "10.0.0.0/8");
+
+    let v6: IpPrefix = "2001:db8::1".parse().unwrap();
+    assert_eq!(v6.family(), Family::V6);
+    assert_eq!(v6.width(), 128);
+    assert_eq!(v6.to_cidr_string().unwrap(), "2001:db8::1/128");
+
+    assert!("10.0.0.0/33".parse::<IpPrefix>().is_err());
+
+    // the version arguments parse into the same families
+    assert_eq!("v6".parse::<Family>().unwrap(), Family::V6);
+    let err = "v5".parse::<Family>().unwrap_err();
+    assert_eq!(NetcalcError::code_of(&err), "unrecognized_version");
+    assert!(cidr_range("v5", "10.0.0.0/8").is_err());
+  }
+
+  #[test]
+  fn test_convert_counted() {
+    let fmt = Format::new(",");
+    let opts = ParseOptions::default();
+    let counted = |ver, s| convert_counted(ver, &fmt, &opts, s).unwrap();
+
+    assert_eq!(counted("v4", "\n# nothing yet\n  \n"), ("".to_string(), 0));
+    assert_eq!(
+      counted("v4", "+10.0.0.0/8\n# gone\n-10.0.0.0/8"),
+      ("".to_string(), 2)
+    );
+    assert_eq!(
+      counted("mixed", "+10.0.0.0/8\n[v6]\n+::/0"),
+      ("10.0.0.0/8,::/0".to_string(), 2)
+    );
+  }
+
+  #[test]
+  fn test_array_output() {
+    let rules = "+10.0.0.0/8\n+192.168.0.0/16";
+    let array = |quote| Format {
+      array_quote: Some(quote),
+      ..Format::new(",")
+    };
+    assert_eq!(
+      convert_fmt("v4", &array('\''), rules).unwrap(),
+      "['10.0.0.0/8', '192.168.0.0/16']"
+    );
+    assert_eq!(
+      convert_fmt("v4", &array('"'), rules).unwrap(),
+      "[\"10.0.0.0/8\", \"192.168.0.0/16\"]"
+    );
+    assert_eq!(convert_fmt("v6", &array('"'), "").unwrap(), "[]");
+  }
+
+  #[test]
+  fn test_hex_notation() {
+    let fmt = Format {
+      notation: Notation::Hex,
+      ..Format::new(",")
+    };
+    assert_eq!(
+      convert_fmt("v4", &fmt, "+10.0.0.0/8\n+192.168.1.1").unwrap(),
+      "0x0A000000/0xFF000000,0xC0A80101/0xFFFFFFFF"
+    );
+    assert_eq!(
+      convert_fmt("v4", &fmt, "+0.0.0.0/0").unwrap(),
+      "0x00000000/0x00000000"
+    );
+    assert!(convert_fmt("v6", &fmt, "+::/0").is_err());
+  }
+
+  #[test]
+  fn test_padded_notation() {
+    let fmt = Format {
+      notation: Notation::Padded,
+      ..Format::new("\n")
+    };
+    assert_eq!(
+      convert_fmt("v4", &fmt, "+10.0.0.0/8\n+192.168.1.1\n+0.0.0.0/0").unwrap(),
+      "000.000.000.000/00"
+    );
+    assert_eq!(
+      convert_fmt("v4", &fmt, "+10.0.0.0/8\n+192.168.1.1").unwrap(),
+      "010.000.000.000/08\n192.168.001.001/32"
+    );
+
+    let fmt = Format {
+      len_sep: Some(" ".to_string()),
+      ..fmt
+    };
+    assert_eq!(
+      convert_fmt("v4", &fmt, "+172.16.0.0/12").unwrap(),
+      "172.016.000.000 12"
+    );
+    assert!(convert_fmt("v6", &fmt, "+::/0").is_err());
+  }
+
+  #[test]
+  fn test_address_count() {
+    assert_eq!(convert("v4", ",", "+10.0.0.0+256").unwrap(), "10.0.0.0/24");
+    assert_eq!(
+      convert("v4", ",", "+10.0.0.255+3").unwrap(),
+      "10.0.0.255/32,10.0.1.0/31"
+    );
+    assert_eq!(convert("v6", ",", "+::+1").unwrap(), "::/128");
+
+    let code = |s| NetcalcError::code_of(&convert("v4", ",", s).unwrap_err());
+    assert_eq!(code("+10.0.0.0+0"), "invalid_range");
+    assert_eq!(code("+255.255.255.255+2"), "invalid_range");
+    assert_eq!(code("+10.0.0.0+x"), "invalid_range");
+  }
+
+  #[test]
+  fn test_wildcard() {
+    let out = convert("v4", ",", "+10.*.5.0/24\n-10.1.*.*/16").unwrap();
+    let out = out.split(',').collect::<Vec<_>>();
+    assert_eq!(out.len(), 255);
+    assert_eq!(out[..2], ["10.0.5.0/24", "10.2.5.0/24"]);
+
+    assert_eq!(convert("v4", ",", "+10.*.*.*").unwrap(), "10.0.0.0/8");
+    assert!(convert("v6", ",", "+2001:*::/32").is_err());
+  }
+
+  #[test]
+  fn test_nat64_cidr() {
+    let prefix = V6::parse_cidr("64:ff9b::192.0.2.0/120").unwrap();
+    assert_eq!(prefix.len(), 120);
+    assert_eq!(
+      V6::cidr_from_prefix(prefix).unwrap(),
+      "64:ff9b::c000:200/120"
+    );
+
+    // host bits in the embedded ipv4 tail are truncated away
+    assert_eq!(
+      convert("v6", "\n", "+64:ff9b::192.0.2.77/120").unwrap(),
+      "64:ff9b::c000:200/120"
+    );
+    assert_eq!(
+      convert("v6", "\n", "+64:ff9b::192.0.2.0-64:ff9b::192.0.2.255").unwrap(),
+      "64:ff9b::c000:200/120"
+    );
+  }
+
+  #[test]
+  fn test_max_line_len() {
+    let long = format!("+10.0.0.0/8 | {}", "x".repeat(2000));
+    let err = convert("v4", ",", &long).unwrap_err();
+    assert_eq!(NetcalcError::code_of(&err), "line_too_long");
+    assert!(convert("mixed", ",", &long).is_err());
+    assert!(count_prefixes("v4", &long).is_err());
+
+    // just under the limit is fine, and the limit can be lifted
+    let padded = format!("+10.0.0.0/8 {}", " ".repeat(1000));
+    assert_eq!(convert("v4", ",", &padded).unwrap(), "10.0.0.0/8");
+    let unlimited = ParseOptions {
+      max_line_len: None,
+      ..ParseOptions::default()
+    };
+    let fmt = Format::new(",");
+    assert_eq!(
+      convert_with("v4", &fmt, &unlimited, &long).unwrap(),
+      "10.0.0.0/8"
+    );
+
+    // the long line is skipped without being read in whole, and the
+    // lines after it still come through
+    let input = format!("+10.0.0.0/8\r\n{}\n+11.0.0.0/8", "x".repeat(1 << 20));
+    let mut lines = ParseOptions::default().lines(input.as_bytes());
+    assert_eq!(lines.next().unwrap().unwrap(), "+10.0.0.0/8");
+    let err = lines.next().unwrap().unwrap_err();
+    assert_eq!(
+      format!("{}", err),
+      "Line too long: 1048576 bytes, at most 1024 allowed"
+    );
+    assert!(lines.buf.capacity() < 1 << 12);
+    assert_eq!(lines.next().unwrap().unwrap(), "+11.0.0.0/8");
+    assert!(lines.next().is_none());
+
+    // a line of exactly the limit is fine, before a "\r\n" too
+    let exact = format!("+10.0.0.0/8{}", " ".repeat(1024 - 11));
+    let input = format!("{}\r\n{}", exact, exact);
+    assert_eq!(convert("v4", ",", &input).unwrap(), "10.0.0.0/8");
+    let (tree, errors) =
+      build_tree_lossy("v4", &format!("{} \n+10.0.0.0/8", exact)).unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(NetcalcError::code_of(&errors[0].1), "line_too_long");
+    assert_eq!(tree.count_addresses(32), 1 << 24);
+
+    // every reader has the limit, and takes the options to lift it
+    let too_long = |err: failure::Error| {
+      assert_eq!(NetcalcError::code_of(&err), "line_too_long");
+    };
+    let rpsl = format!("route: 10.0.0.0/8\nremarks: {}", "x".repeat(2000));
+    too_long(convert_rpsl(",", rpsl.as_bytes()).unwrap_err());
+    let csv = format!("10.0.0.0,10.0.0.255,{}", "x".repeat(2000));
+    too_long(convert_csv_ranges(",", 0, 1, csv.as_bytes()).unwrap_err());
+    too_long(check_host_bits("v4", long.as_bytes()).unwrap_err());
+    too_long(aggregate("v4", ",", long.as_bytes()).unwrap_err());
+    too_long(explain("v4", ",", long.as_bytes()).unwrap_err());
+    too_long(free(",", "10.0.0.0/8", long.as_bytes()).unwrap_err());
+
+    assert_eq!(
+      convert_rpsl_with(&fmt, &unlimited, rpsl.as_bytes()).unwrap(),
+      "10.0.0.0/8"
+    );
+    assert_eq!(
+      convert_csv_ranges_with(&fmt, &unlimited, 0, 1, csv.as_bytes()).unwrap(),
+      "10.0.0.0/24"
+    );
+    assert_eq!(
+      aggregate_with("v4", &fmt, &unlimited, long.as_bytes()).unwrap(),
+      "10.0.0.0/8"
+    );
+    assert_eq!(count_prefixes_with("v4", &unlimited, &long).unwrap(), 1);
+    assert_eq!(
+      free_with(&fmt, &unlimited, "10.0.0.0/7", long.as_bytes()).unwrap(),
+      "11.0.0.0/8"
+    );
+  }
+
+  #[test]
+  fn test_hosts_in_prefix() {
+    assert_eq!(subnets_of_length(24, 32), 256);
+    assert_eq!(hosts_in_prefix(24, 32), 254);
+    assert_eq!(hosts_in_prefix(31, 32), 2);
+    assert_eq!(hosts_in_prefix(32, 32), 1);
+    assert_eq!(hosts_in_prefix(30, 32), 2);
+    assert_eq!(hosts_in_prefix(33, 32), 0);
+
+    assert_eq!(hosts_in_prefix(64, 128), 1 << 64);
+    assert_eq!(hosts_in_prefix(0, 128), u128::MAX);
+
+    assert_eq!(subnets_of_length(24, 26), 4);
+    assert_eq!(subnets_of_length(24, 24), 1);
+    assert_eq!(subnets_of_length(26, 24), 0);
+  }
+
+  #[test]
+  fn test_build_tree_lossy() {
+    let input = "+10.0.0.0/24\n+10.0.1.0/33\n:default +\n10.0.1.0/24\noops\n";
+    let (tree, errors) = build_tree_lossy("v4", input).unwrap();
+    assert_eq!(
+      App::<V4>::render(&Format::new(","), tree).unwrap(),
+      "10.0.0.0/23"
+    );
+    let lines = errors.iter().map(|(line, _)| *line).collect::<Vec<_>>();
+    assert_eq!(lines, [2, 5]);
+    assert_eq!(NetcalcError::code_of(&errors[0].1), "invalid_cidr");
+
+    // the fail-fast variant stops at the first bad line
+    assert!(build_tree("v4", input).is_err());
+    assert!(build_tree_lossy("v5", input).is_err());
+  }
+
+  #[test]
+  fn test_reverse() {
+    let input = "+10.0.0.0/24\n+10.0.2.0/24\n+192.168.0.0/16\n+10.1.0.0/26";
+    let fmt = Format {
+      reverse: true,
+      ..Format::new(",")
+    };
+    assert_eq!(
+      convert_fmt("v4", &fmt, input).unwrap(),
+      "192.168.0.0/16,10.1.0.0/26,10.0.2.0/24,10.0.0.0/24"
+    );
+
+    let array = Format {
+      array_quote: Some('"'),
+      ..fmt.clone()
+    };
+    assert_eq!(
+      convert_fmt("v4", &array, "+10.0.0.0/24\n+10.0.2.0/24").unwrap(),
+      r#"["10.0.2.0/24", "10.0.0.0/24"]"#
+    );
+
+    let grouped = Format {
+      group_len: Some(16),
+      ..fmt
+    };
+    assert_eq!(
+      convert_fmt("v4", &grouped, input).unwrap(),
+      "192.168.0.0/16\n10.1.0.0/16\n  10.1.0.0/26\n\
+       10.0.0.0/16\n  10.0.2.0/24\n  10.0.0.0/24"
+    );
+  }
+
+  #[test]
+  fn test_check_host_bits() {
+    let input = "10.0.0.5/24\n+10.0.1.0/24\n# 10.0.2.1/24\n\
+                 10.0.3.0-10.0.3.9\n- 10.0.4.1/31";
+    let findings = check_host_bits("v4", input.as_bytes()).unwrap();
+    let finding = |line, normalized: &str, host_bits_set| HostBitsFinding {
+      line,
+      normalized: normalized.to_string(),
+      host_bits_set,
+    };
+    assert_eq!(
+      findings,
+      vec![
+        finding(1, "10.0.0.0/24", true),
+        finding(2, "10.0.1.0/24", false),
+        finding(5, "10.0.4.0/31", true),
+      ]
+    );
+
+    let findings = check_host_bits("v6", "2001:db8::1/64".as_bytes()).unwrap();
+    assert_eq!(findings, vec![finding(1, "2001:db8::/64", true)]);
+
+    // a length list is checked for each length
+    let findings = check_host_bits("v4", "10.0.0.128/24,25".as_bytes());
+    assert_eq!(
+      findings.unwrap(),
+      vec![
+        finding(1, "10.0.0.0/24", true),
+        finding(1, "10.0.0.128/25", false),
+      ]
+    );
+
+    let err = check_host_bits("v4", "10.0.0.0/33".as_bytes()).unwrap_err();
+    assert_eq!(NetcalcError::code_of(&err), "invalid_cidr");
+
+    // lines are read as convert reads them
+    let input = "#10.0.0.1/24\nadd 10.0.0.5/24\ndel\t10.0.1.0/24\n\
+                 10.0.2.5/24 | note\n!10.0.3.1/24\nnoop 10.0.4.1/24\n\
+                 :def corp = 10.0.5.1/24\ncorp";
+    assert_eq!(
+      check_host_bits("v4", input.as_bytes()).unwrap(),
+      vec![
+        finding(2, "10.0.0.0/24", true),
+        finding(3, "10.0.1.0/24", false),
+        finding(4, "10.0.2.0/24", true),
+      ]
+    );
+    let err = check_host_bits("v4", "--10.0.0.1/24".as_bytes()).unwrap_err();
+    assert_eq!(NetcalcError::code_of(&err), "invalid_range");
+  }
+
+  #[test]
+  fn test_fit_range() {
+    let fit = fit_range("10.0.0.1-10.0.0.6", 4).unwrap();
+    assert!(fit.fits());
+    assert_eq!(fit.exact_prefixes, 4);
+    assert_eq!(fit.prefixes.len(), 4);
+
+    let fit = fit_range("10.0.0.1-10.0.0.6", 3).unwrap();
+    assert!(!fit.fits());
+    assert_eq!(fit.exact_prefixes, 4);
+    assert_eq!(fit.prefixes, ["10.0.0.0/30", "10.0.0.4/31", "10.0.0.6/32"]);
+    assert_eq!(fit.extra_addresses, 1);
+
+    let fit = fit_range("2001:db8::1-2001:db8::ffff", 1).unwrap();
+    assert_eq!(fit.prefixes, ["2001:db8::/112"]);
+    assert_eq!(fit.extra_addresses, 1);
+
+    let fit = fit_range("::-ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff", 1);
+    let fit = fit.unwrap();
+    assert!(fit.fits());
+    assert_eq!(fit.prefixes, ["::/0"]);
+
+    let fit = fit_range("::1-ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff", 1);
+    let fit = fit.unwrap();
+    assert_eq!(fit.exact_prefixes, 128);
+    assert_eq!(fit.prefixes, ["::/0"]);
+    assert_eq!(fit.extra_addresses, 1);
+
+    assert!(fit_range("10.0.0.1-10.0.0.6", 0).is_err());
+    let err = fit_range("10.0.0.6-10.0.0.1", 1).unwrap_err();
+    assert_eq!(NetcalcError::code_of(&err), "invalid_range");
+  }
+
+  #[test]
+  fn test_length_list() {
+    let prefixes = parse_length_list::<V4>("10.0.0.64/24,25,26").unwrap();
+    let cidrs = prefixes
+      .into_iter()
+      .map(|p| V4::cidr_from_prefix(p).unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(cidrs, ["10.0.0.0/24", "10.0.0.0/25", "10.0.0.64/26"]);
+
+    assert_eq!(
+      convert("v4", ",", "+10.0.0.0/8\n-10.0.0.0/9,10").unwrap(),
+      "10.128.0.0/9"
+    );
+    assert_eq!(
+      convert("v6", ",", "+2001:db8::/48, 64").unwrap(),
+      "2001:db8::/48"
+    );
+
+    let err = convert("v4", ",", "+10.0.0.0/24,33").unwrap_err();
+    assert_eq!(NetcalcError::code_of(&err), "invalid_cidr");
+    assert!(convert("v4", ",", "+10.0.0.0/24,").is_err());
+  }
+
+  #[test]
+  fn test_density_report() {
+    let report =
+      density_report("v4", "+10.0.0.0/16\n+10.1.0.0/24\n+12.0.0.0/7").unwrap();
+    assert_eq!(
+      report,
+      [
+        ("10.0.0.0/8".to_string(), (1 << 16) + (1 << 8)),
+        ("12.0.0.0/8".to_string(), 1 << 24),
+        ("13.0.0.0/8".to_string(), 1 << 24),
+      ]
+    );
+
+    let report = density_report("v6", "+2001:db8::/48").unwrap();
+    assert_eq!(report, [("2001::/16".to_string(), 1 << 80)]);
+    assert!(density_report("v4", "").unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_macros() {
+    let rules = "\
+      :def corp = 10.0.0.0/8, 192.168.0.0/16\n\
+      :def lab = 10.1.0.0/16,17\n\
+      :def all_corp = corp, 172.16.0.0/12\n\
+      +all_corp\n\
+      -lab\n";
+    assert_eq!(
+      convert("v4", ",", rules).unwrap(),
+      "10.0.0.0/16,10.2.0.0/15,10.4.0.0/14,10.8.0.0/13,10.16.0.0/12,\
+       10.32.0.0/11,10.64.0.0/10,10.128.0.0/9,172.16.0.0/12,192.168.0.0/16"
+    );
+
+    // with a default operator, a bare name is an operand too
+    let rules = ":default +\n:def a = 10.0.0.0/24\na\n10.0.1.0/24";
+    assert_eq!(convert("v4", ",", rules).unwrap(), "10.0.0.0/23");
+
+    // a definition only sees the earlier ones, including its own
+    let rules = ":def a = 10.0.0.0/24\n:def a = a, 10.0.1.0/24\n+a";
+    assert_eq!(convert("v4", ",", rules).unwrap(), "10.0.0.0/23");
+    let err = convert("v4", ",", ":def a = b\n:def b = a").unwrap_err();
+    assert_eq!(NetcalcError::code_of(&err), "unknown_macro");
+
+    let err = convert("v4", ",", "+corp").unwrap_err();
+    assert_eq!(NetcalcError::code_of(&err), "unknown_macro");
+    assert!(format!("{}", err).contains(":def corp ="));
+    assert!(convert("v4", ",", ":def 1x = 10.0.0.0/8").is_err());
+    assert!(convert("v4", ",", ":def x 10.0.0.0/8").is_err());
+    assert!(convert("v4", ",", ":def noop = 10.0.0.0/8").is_err());
+
+    // the word operators are never taken for macros
+    let rules = ":default +\nnoop\n10.0.0.0/8";
+    assert_eq!(convert("v4", ",", rules).unwrap(), "10.0.0.0/8");
+    let out = aggregate("v4", ",", "noop\n10.0.0.0/8".as_bytes()).unwrap();
+    assert_eq!(out, "10.0.0.0/8");
+
+    // nor is the metadata part of the name
+    let rules = ":def corp = 10.0.0.0/8\n+corp | note";
+    assert_eq!(convert("v4", ",", rules).unwrap(), "10.0.0.0/8");
+
+    // the word operators take macros like the sigils do
+    let rules = ":def corp = 10.0.0.0/8\n:def lab = 10.0.0.0/9\n\
+                 add corp\nDEL\tlab";
+    assert_eq!(convert("v4", ",", rules).unwrap(), "10.128.0.0/9");
+    let err = convert("v4", ",", "add corp").unwrap_err();
+    assert_eq!(NetcalcError::code_of(&err), "unknown_macro");
+  }
+
+  #[test]
+  fn test_exclude_range() {
+    let range = "10.0.0.1-10.0.0.10";
+    let out = exclude_range("\n", range, Some("10.0.0.0/24")).unwrap();
+    assert_eq!(
+      out,
+      "10.0.0.0/32\n10.0.0.11/32\n10.0.0.12/30\n10.0.0.16/28\n\
+       10.0.0.32/27\n10.0.0.64/26\n10.0.0.128/25"
+    );
+
+    // together with the range it tiles the universe, without overlap
+    let rules = |out: &str, last: &str| {
+      let mut rules =
+        out.lines().map(|l| format!("+{}\n", l)).collect::<String>();
+      rules.push_str(last);
+      rules
+    };
+    let both = rules(&out, &format!("+{}", range));
+    assert_eq!(convert("v4", ",", &both).unwrap(), "10.0.0.0/24");
+    let overlap = rules(&out, &format!("&{}", range));
+    assert_eq!(convert("v4", ",", &overlap).unwrap(), "");
+
+    let out =
+      exclude_range(",", "::1-ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff", None);
+    assert_eq!(out.unwrap(), "::/128");
+    let err =
+      exclude_range(",", "10.0.0.1-10.0.0.10", Some("::/0")).unwrap_err();
+    assert_eq!(NetcalcError::code_of(&err), "invalid_cidr");
+  }
+
+  #[test]
+  fn test_prefix_to_cidr_string() {
+    let tree = build_tree("v4", "+10.0.0.0/8\n+192.168.1.1").unwrap();
+    let cidrs = tree
+      .prefixes()
+      .iter()
+      .map(|p| p.to_cidr_string(Family::V4).unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(cidrs, ["10.0.0.0/8", "192.168.1.1/32"]);
+
+    let tree = build_tree("v6", "+2001:db8::/32\n+::1").unwrap();
+    let cidrs = tree
+      .prefixes()
+      .iter()
+      .map(|p| p.to_cidr_string(Family::V6).unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(cidrs, ["::1/128", "2001:db8::/32"]);
+    assert_eq!(Prefix::empty().to_cidr_string(Family::V6).unwrap(), "::/0");
+
+    // a v6 prefix too long for v4
+    assert!(tree.prefixes()[0].to_cidr_string(Family::V4).is_err());
+  }
+
+  #[test]
+  fn test_convert_sections() {
+    let input = "\
+      +10.0.0.0/24\n\
+      :default +\n\
+      10.0.1.0/24\n\
+      --- without the second\n\
+      +10.0.0.0/24\n\
+      ---\n\
+      10.0.2.0/24\n";
+    let err = convert_sections("v4", ",", input.as_bytes()).unwrap_err();
+    assert_eq!(NetcalcError::code_of(&err), "unrecognized_line");
+
+    let input = input.replace("10.0.2.0/24", "+10.0.2.0/24\n-10.0.2.0/25");
+    assert_eq!(
+      convert_sections("v4", ",", input.as_bytes()).unwrap(),
+      "--- section 1\n10.0.0.0/23\n\
+       --- without the second\n10.0.0.0/24\n\
+       --- section 3\n10.0.2.128/25"
+    );
+
+    // a leading separator doesn't make an empty first section
+    let input = "\n--- a\n+::/0\n--- b\n";
+    assert_eq!(
+      convert_sections("v6", ",", input.as_bytes()).unwrap(),
+      "--- a\n::/0\n--- b"
+    );
+  }
+
+  #[test]
+  fn test_integer_range() {
+    assert_eq!(
+      convert("v4", ",", "+3232235521-3232235530").unwrap(),
+      "192.168.0.1/32,192.168.0.2/31,192.168.0.4/30,192.168.0.8/31,\
+       192.168.0.10/32"
+    );
+    // the forms can be mixed, and 0 and 2^32-1 are the bounds
+    assert_eq!(convert("v4", ",", "+0-0.255.255.255").unwrap(), "0.0.0.0/8");
+    assert_eq!(
+      convert("v4", ",", "+4278190080-4294967295").unwrap(),
+      "255.0.0.0/8"
+    );
+
+    let code = |s| NetcalcError::code_of(&convert("v4", ",", s).unwrap_err());
+    assert_eq!(code("+3232235530-3232235521"), "invalid_range");
+    assert_eq!(code("+0-4294967296"), "invalid_range");
+    assert_eq!(code("+-1-5"), "invalid_range");
+  }
+
+  #[test]
+  fn test_convert_csv_ranges() {
+    let csv = "\
+      \"start\",\"end\",\"start_num\",\"end_num\",\"cc\",\"country\"\n\
+      \"1.0.0.0\",\"1.0.0.255\",\"16777216\",\"16777471\",\"AU\",\"Australia\"\n\
+      \"1.0.1.0\",\"1.0.3.255\",\"16777472\",\"16778239\",\"CN\",\"China\"\n\
+      \"1.0.4.0\",\"1.0.4.9\",\"16778240\",\"16778249\",\"KR\",\"Korea, Republic of\"\n\
+      \n";
+    let expected = "1.0.0.0/22,1.0.4.0/29,1.0.4.8/31";
+    assert_eq!(
+      convert_csv_ranges(",", 0, 1, csv.as_bytes()).unwrap(),
+      expected
+    );
+    assert_eq!(
+      convert_csv_ranges(",", 2, 3, csv.as_bytes()).unwrap(),
+      expected
+    );
+
+    let csv = "2001:db8::,2001:db8::ffff\n10.0.0.0,10.0.0.255\n";
+    assert_eq!(
+      convert_csv_ranges(",", 0, 1, csv.as_bytes()).unwrap(),
+      "10.0.0.0/24,2001:db8::/112"
+    );
+
+    // only the first row may be a header
+    let csv = "1.0.0.0,1.0.0.255\nstart,end\n";
+    let err = convert_csv_ranges(",", 0, 1, csv.as_bytes()).unwrap_err();
+    assert_eq!(NetcalcError::code_of(&err), "invalid_range");
+    assert!(convert_csv_ranges(",", 0, 7, csv.as_bytes()).is_err());
+  }
+
+  #[test]
+  fn test_len_sep() {
+    let fmt = Format {
+      len_sep: Some(" ".to_string()),
+      ..Format::new(",")
+    };
+    assert_eq!(
+      convert_fmt("v4", &fmt, "+10.0.0.0/8\n+192.168.1.1").unwrap(),
+      "10.0.0.0 8,192.168.1.1 32"
+    );
+    assert_eq!(
+      convert_fmt("v6", &fmt, "+2001:db8::/32\n+::/0").unwrap(),
+      ":: 0"
+    );
+    assert_eq!(
+      convert_fmt("v6", &fmt, "+2001:db8::/32").unwrap(),
+      "2001:db8:: 32"
+    );
+
+    let fmt = Format {
+      len_sep: Some("|".to_string()),
+      ..Format::new(",")
+    };
+    assert_eq!(
+      convert_fmt("v4", &fmt, "+10.0.0.0/8").unwrap(),
+      "10.0.0.0|8"
+    );
+  }
+
+  #[test]
+  fn test_convert_preview() {
+    let fmt = Format::new(",");
+    let opts = ParseOptions::default();
+    let rules = "+0.0.0.0/0\n-10.0.0.1\n-192.168.0.0/16";
+    let (out, left_out) = convert_preview("v4", &fmt, &opts, rules, 3).unwrap();
+    assert_eq!(out, "0.0.0.0/5,8.0.0.0/7,10.0.0.0/32");
+    assert_eq!(left_out, count_prefixes("v4", rules).unwrap() - 3);
+
+    let (out, left_out) =
+      convert_preview("v4", &fmt, &opts, rules, 1000).unwrap();
+    assert_eq!(out, convert("v4", ",", rules).unwrap());
+    assert_eq!(left_out, 0);
+
+    assert_eq!(
+      convert_preview("v6", &fmt, &opts, "", 10).unwrap(),
+      (String::new(), 0)
+    );
+    assert!(convert_preview("v5", &fmt, &opts, "", 10).is_err());
+  }
+
+  #[test]
+  fn test_exclusive_range() {
+    assert_eq!(
+      convert("v4", ",", "+10.0.0.0..10.0.1.0").unwrap(),
+      "10.0.0.0/24"
+    );
+    assert_eq!(
+      convert("v4", ",", "+10.0.0.0-10.0.1.0").unwrap(),
+      "10.0.0.0/24,10.0.1.0/32"
+    );
+    assert_eq!(
+      convert("v4", ",", "+10.0.0.0/24..10.0.2.0/24").unwrap(),
+      "10.0.0.0/23"
+    );
+    assert_eq!(
+      convert("v6", ",", "+2001:db8::..2001:db8::100").unwrap(),
+      "2001:db8::/120"
+    );
+    assert_eq!(convert("v4", ",", "+10.0.0.1..10.0.0.1").unwrap(), "");
+
+    let code = |s| NetcalcError::code_of(&convert("v4", ",", s).unwrap_err());
+    assert_eq!(code("+10.0.0.2..10.0.0.1"), "invalid_range");
+    assert_eq!(code("+10.0.0.1..10.0.0.1..10.0.0.5"), "invalid_range");
   }
 }
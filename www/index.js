@@ -1,4 +1,4 @@
-const { convert } = wasm_bindgen;
+const { convert, convert_with_trailing_sep } = wasm_bindgen;
 
 async function conv() {
   await wasm_bindgen("./netcalc_bg.wasm");
@@ -6,11 +6,12 @@ async function conv() {
   const input = document.querySelector("#rules textarea").value;
   let ver = document.querySelector("#version").value;
 
-  let sep = document.querySelector("#separator").value;
-  if (sep == "\\n") {
-    sep = "\n";
-  }
-  const output = convert(ver, sep, input);
+  // escapes like "\n" in the separator are interpreted by convert
+  const sep = document.querySelector("#separator").value;
+  const trailing = document.querySelector("#trailing").checked;
+  const output = trailing
+    ? convert_with_trailing_sep(ver, sep, input)
+    : convert(ver, sep, input);
   document.querySelector("#results textarea").value = output;
 }
 